@@ -0,0 +1,610 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Transcript annotations supplied alongside the transcriptome FASTA.
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead};
+use std::path::Path;
+
+use bio::io::fasta;
+use debruijn::dna_string::DnaString;
+use debruijn::Mer;
+use failure::Error;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::error::MappingError;
+use crate::pseudoaligner::Strand;
+use crate::utils::open_with_gz;
+
+/// Name and biotype of a gene, where the annotation provides them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct GeneInfo {
+    pub gene_name: Option<String>,
+    pub gene_biotype: Option<String>,
+}
+
+/// Kind of sequence an index target was made from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TargetKind {
+    // a spliced transcript
+    Mature,
+    // an intron of a gene, with flanks reaching into the neighbouring exons
+    Intron,
+}
+
+/// Exons of an annotated transcript, in 1-based inclusive genome
+/// coordinates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TranscriptModel {
+    pub tx_id: String,
+    pub kind: TargetKind,
+    pub chrom: String,
+    pub strand: Strand,
+    // sorted by start
+    pub exons: Vec<(usize, usize)>,
+}
+
+/// Genes and exons of the transcripts in a GTF or GFF3 file.
+#[derive(Debug, Default)]
+pub struct Annotation {
+    // transcript id -> gene id
+    pub tx_gene_mapping: HashMap<String, String>,
+    // gene id -> name and biotype
+    pub genes: HashMap<String, GeneInfo>,
+    // transcripts with exon records, in the order of the file
+    pub transcripts: Vec<TranscriptModel>,
+}
+
+impl Annotation {
+    /// Read a GTF or GFF3 file. Transcripts are linked to their gene through
+    /// the `gene_id` attribute, or in GFF3 through the `Parent` of the
+    /// transcript record. Ensembl transcript versions are appended to the
+    /// transcript ids, to match the ids of the cDNA FASTA.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Annotation, Error> {
+        let reader = open_with_gz(&path)?;
+        let mut annotation = Annotation::default();
+        // GFF3 gene record id -> gene id, and transcripts known only by parent
+        let mut gene_records = HashMap::new();
+        let mut tx_parents = Vec::new();
+        // GFF3 transcript record id -> transcript id, and exons known only by
+        // parent
+        let mut tx_records = HashMap::new();
+        let mut exon_parents = Vec::new();
+        // transcript id -> position in `transcripts`
+        let mut tx_models = HashMap::new();
+
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line.map_err(|err| MappingError::io(path.as_ref(), err))?;
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 9 {
+                return Err(MappingError::Parse(format!(
+                    "{:?} line {}: expected 9 tab separated fields, found {}",
+                    path.as_ref(),
+                    line_num + 1,
+                    fields.len()
+                ))
+                .into());
+            }
+            // gene, ncRNA_gene, pseudogene
+            let is_gene = fields[2].ends_with("gene");
+            let attributes = gtf_attributes(fields[8]);
+
+            let record_id = attributes.get("ID");
+            let gene_id = match (attributes.get("gene_id"), record_id) {
+                (Some(gene_id), _) => Some(gene_id.to_string()),
+                (None, Some(id)) if is_gene => Some(strip_id_prefix(id).to_string()),
+                _ => None,
+            };
+
+            let tx_id = attributes.get("transcript_id").map(|tx_id| {
+                let version = attributes
+                    .get("transcript_version")
+                    .or_else(|| attributes.get("version").filter(|_| !is_gene));
+                match version {
+                    Some(version) if !tx_id.contains('.') => format!("{}.{}", tx_id, version),
+                    _ => tx_id.to_string(),
+                }
+            });
+
+            if fields[2] == "exon" {
+                let coord = |field: &str| {
+                    field.parse::<usize>().map_err(|_| {
+                        MappingError::Parse(format!(
+                            "{:?} line {}: invalid coordinate {}",
+                            path.as_ref(),
+                            line_num + 1,
+                            field
+                        ))
+                    })
+                };
+                let exon = Exon {
+                    chrom: fields[0].to_string(),
+                    strand: if fields[6] == "-" {
+                        Strand::Reverse
+                    } else {
+                        Strand::Forward
+                    },
+                    start: coord(fields[3])?,
+                    end: coord(fields[4])?,
+                };
+
+                match (&tx_id, attributes.get("Parent")) {
+                    (Some(tx_id), _) => annotation.add_exon(&mut tx_models, tx_id, exon),
+                    (None, Some(parents)) => {
+                        for parent in parents.split(',') {
+                            exon_parents.push((parent.to_string(), exon.clone()));
+                        }
+                    }
+                    (None, None) => (),
+                }
+            } else if let (Some(id), Some(tx_id)) = (record_id, &tx_id) {
+                tx_records.insert(id.to_string(), tx_id.clone());
+            }
+
+            match gene_id {
+                Some(gene_id) => {
+                    if is_gene {
+                        if let Some(id) = record_id {
+                            gene_records.insert(id.to_string(), gene_id.clone());
+                        }
+                    }
+
+                    let info = annotation.genes.entry(gene_id.clone()).or_default();
+                    if info.gene_name.is_none() {
+                        info.gene_name = attributes
+                            .get("gene_name")
+                            .or_else(|| attributes.get("Name").filter(|_| is_gene))
+                            .or_else(|| attributes.get("gene"))
+                            .map(|name| name.to_string());
+                    }
+                    if info.gene_biotype.is_none() {
+                        info.gene_biotype = attributes
+                            .get("gene_type")
+                            .or_else(|| attributes.get("gene_biotype"))
+                            .or_else(|| attributes.get("biotype").filter(|_| is_gene))
+                            .map(|biotype| biotype.to_string());
+                    }
+
+                    if let Some(tx_id) = tx_id {
+                        annotation.tx_gene_mapping.entry(tx_id).or_insert(gene_id);
+                    }
+                }
+                None => {
+                    if let (Some(tx_id), Some(parent)) = (tx_id, attributes.get("Parent")) {
+                        tx_parents.push((tx_id, parent.to_string()));
+                    }
+                }
+            }
+        }
+
+        for (parent, exon) in exon_parents {
+            if let Some(tx_id) = tx_records.get(&parent) {
+                annotation.add_exon(&mut tx_models, tx_id, exon);
+            }
+        }
+        for model in &mut annotation.transcripts {
+            model.exons.sort();
+        }
+
+        // exons and other children of a transcript don't resolve to a gene
+        for (tx_id, parent) in tx_parents {
+            if let Some(gene_id) = gene_records.get(&parent) {
+                annotation
+                    .tx_gene_mapping
+                    .entry(tx_id)
+                    .or_insert_with(|| gene_id.clone());
+            }
+        }
+
+        info!(
+            "Read {} transcripts of {} genes from {:?}",
+            annotation.tx_gene_mapping.len(),
+            annotation.genes.len(),
+            path.as_ref()
+        );
+        Ok(annotation)
+    }
+
+    fn add_exon(&mut self, tx_models: &mut HashMap<String, usize>, tx_id: &str, exon: Exon) {
+        let transcripts = &mut self.transcripts;
+        let idx = *tx_models.entry(tx_id.to_string()).or_insert_with(|| {
+            transcripts.push(TranscriptModel {
+                tx_id: tx_id.to_string(),
+                kind: TargetKind::Mature,
+                chrom: exon.chrom.clone(),
+                strand: exon.strand,
+                exons: Vec::new(),
+            });
+            transcripts.len() - 1
+        });
+        transcripts[idx].exons.push((exon.start, exon.end));
+    }
+
+    /// Add the introns of each gene as `Intron` targets after the
+    /// transcripts, extended by `flank` bases into the neighbouring exons so
+    /// that they hold the kmers spanning the exon-intron junctions. Introns
+    /// shared by transcripts of a gene are added once, as `<gene_id>-I<n>`
+    /// numbered along the genome. Returns the number of introns added.
+    pub fn add_introns(&mut self, flank: usize) -> usize {
+        type Introns = BTreeSet<(usize, usize)>;
+        // gene id -> chromosome, strand and introns, in order of appearance
+        let mut gene_order = Vec::new();
+        let mut gene_introns: HashMap<&str, (&str, Strand, Introns)> = HashMap::new();
+
+        for model in &self.transcripts {
+            let gene_id = match self.tx_gene_mapping.get(&model.tx_id) {
+                Some(gene_id) if model.kind == TargetKind::Mature => gene_id.as_str(),
+                _ => continue,
+            };
+            let (_, _, introns) = gene_introns.entry(gene_id).or_insert_with(|| {
+                gene_order.push(gene_id);
+                (model.chrom.as_str(), model.strand, BTreeSet::new())
+            });
+            for exons in model.exons.windows(2) {
+                let (intron_start, intron_end) = (exons[0].1 + 1, exons[1].0 - 1);
+                if intron_start <= intron_end {
+                    introns.insert((
+                        intron_start.saturating_sub(flank).max(1),
+                        intron_end + flank,
+                    ));
+                }
+            }
+        }
+
+        let mut intron_models = Vec::new();
+        let mut intron_genes = Vec::new();
+        for gene_id in gene_order {
+            let (chrom, strand, introns) = &gene_introns[gene_id];
+            for (n, &intron) in introns.iter().enumerate() {
+                let tx_id = format!("{}-I{}", gene_id, n + 1);
+                intron_genes.push((tx_id.clone(), gene_id.to_string()));
+                intron_models.push(TranscriptModel {
+                    tx_id,
+                    kind: TargetKind::Intron,
+                    chrom: chrom.to_string(),
+                    strand: *strand,
+                    exons: vec![intron],
+                });
+            }
+        }
+
+        let num_introns = intron_models.len();
+        self.transcripts.extend(intron_models);
+        self.tx_gene_mapping.extend(intron_genes);
+        info!("Added {} introns as targets", num_introns);
+        num_introns
+    }
+
+    /// Splice the annotated transcripts out of a genome FASTA. Exons are
+    /// joined in genome order and transcripts on the reverse strand are
+    /// reverse complemented. Returns the transcript sequences, names and genes
+    /// in the order of the annotation, like `utils::read_transcripts`.
+    pub fn splice_transcripts<R: io::Read>(
+        &self,
+        genome: fasta::Reader<R>,
+    ) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>), Error> {
+        let mut chrom_transcripts: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (idx, model) in self.transcripts.iter().enumerate() {
+            chrom_transcripts
+                .entry(model.chrom.as_str())
+                .or_default()
+                .push(idx);
+        }
+
+        info!(
+            "Splicing {} transcripts from genome",
+            self.transcripts.len()
+        );
+        let mut spliced: Vec<Option<DnaString>> = self.transcripts.iter().map(|_| None).collect();
+        for result in genome.records() {
+            let record = result
+                .map_err(|err| MappingError::Parse(format!("genome FASTA record: {}", err)))?;
+            let chrom_seq = record.seq();
+
+            for &idx in chrom_transcripts.get(record.id()).into_iter().flatten() {
+                let model = &self.transcripts[idx];
+                let mut tx_seq = Vec::new();
+                for &(start, end) in &model.exons {
+                    if start == 0 || start > end || end > chrom_seq.len() {
+                        return Err(MappingError::Annotation(format!(
+                            "exon {}-{} of transcript {} lies outside of {} ({} bases)",
+                            start,
+                            end,
+                            model.tx_id,
+                            model.chrom,
+                            chrom_seq.len()
+                        ))
+                        .into());
+                    }
+                    // soft-masked bases are lower case
+                    tx_seq.extend(chrom_seq[start - 1..end].iter().map(u8::to_ascii_uppercase));
+                }
+
+                let seq = DnaString::from_acgt_bytes_hashn(&tx_seq, model.tx_id.as_bytes());
+                spliced[idx] = Some(match model.strand {
+                    Strand::Forward => seq,
+                    Strand::Reverse => seq.rc(),
+                });
+            }
+        }
+
+        let mut seqs = Vec::with_capacity(spliced.len());
+        let mut tx_names = Vec::with_capacity(spliced.len());
+        let mut tx_gene_map = HashMap::new();
+        for (model, seq) in self.transcripts.iter().zip(spliced) {
+            let seq = seq.ok_or_else(|| {
+                MappingError::Annotation(format!(
+                    "sequence {} of transcript {} is missing from the genome",
+                    model.chrom, model.tx_id
+                ))
+            })?;
+            seqs.push(seq);
+            tx_names.push(model.tx_id.clone());
+            if let Some(gene_id) = self.tx_gene_mapping.get(&model.tx_id) {
+                tx_gene_map.insert(model.tx_id.clone(), gene_id.clone());
+            }
+        }
+
+        info!("Done splicing; Found {} sequences", seqs.len());
+        Ok((seqs, tx_names, tx_gene_map))
+    }
+
+    /// Kind of each of the targets `tx_names`, in that order. Targets without
+    /// exon records are taken to be mature transcripts.
+    pub fn target_kinds(&self, tx_names: &[String]) -> Vec<TargetKind> {
+        let kinds: HashMap<&str, TargetKind> = self
+            .transcripts
+            .iter()
+            .map(|model| (model.tx_id.as_str(), model.kind))
+            .collect();
+        tx_names
+            .iter()
+            .map(|tx_name| {
+                kinds
+                    .get(tx_name.as_str())
+                    .cloned()
+                    .unwrap_or(TargetKind::Mature)
+            })
+            .collect()
+    }
+
+    /// Fail unless every transcript in `tx_names` is annotated.
+    pub fn check_transcripts(&self, tx_names: &[String]) -> Result<(), Error> {
+        let missing: Vec<&str> = tx_names
+            .iter()
+            .filter(|tx_name| !self.tx_gene_mapping.contains_key(*tx_name))
+            .map(|tx_name| tx_name.as_str())
+            .collect();
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(MappingError::Annotation(format!(
+                "{} of {} transcripts are missing from the annotation, e.g. {}",
+                missing.len(),
+                tx_names.len(),
+                missing[..missing.len().min(5)].join(", ")
+            ))
+            .into())
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Exon {
+    chrom: String,
+    strand: Strand,
+    start: usize,
+    end: usize,
+}
+
+/// Read a transcript to gene map, either a TSV with the transcript and gene
+/// ids in the first two columns, or a GTF, whose records are mapped through
+/// their `transcript_id` and `gene_id` attributes. Lines starting with `#`
+/// are skipped.
+pub fn read_tx2gene<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>, Error> {
+    let reader = open_with_gz(&path)?;
+    let mut tx2gene = HashMap::new();
+
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| MappingError::io(path.as_ref(), err))?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() == 9 {
+            // GTF: only records of a transcript carry both ids
+            let attributes = gtf_attributes(fields[8]);
+            if let (Some(tx_id), Some(gene_id)) =
+                (attributes.get("transcript_id"), attributes.get("gene_id"))
+            {
+                tx2gene.insert(tx_id.to_string(), gene_id.to_string());
+            }
+        } else {
+            let mut tokens = line.split_whitespace();
+            match (tokens.next(), tokens.next()) {
+                (Some(tx_id), Some(gene_id)) => {
+                    tx2gene.insert(tx_id.to_string(), gene_id.to_string());
+                }
+                _ => {
+                    return Err(MappingError::Parse(format!(
+                        "{:?} line {}: expected a transcript and a gene id",
+                        path.as_ref(),
+                        line_num + 1
+                    ))
+                    .into())
+                }
+            }
+        }
+    }
+
+    info!(
+        "Read gene ids of {} transcripts from {:?}",
+        tx2gene.len(),
+        path.as_ref()
+    );
+    Ok(tx2gene)
+}
+
+/// Attributes of a GTF or GFF3 record: `key "value";` pairs in GTF and
+/// `key=value;` pairs in GFF3.
+fn gtf_attributes(field: &str) -> HashMap<&str, &str> {
+    field
+        .split(';')
+        .filter_map(|attribute| {
+            let attribute = attribute.trim();
+            let sep = match (attribute.find('='), attribute.find(' ')) {
+                (Some(eq), Some(space)) if eq < space => '=',
+                (Some(_), None) => '=',
+                _ => ' ',
+            };
+            let mut tokens = attribute.splitn(2, sep);
+            let key = tokens.next()?;
+            let value = tokens.next()?.trim().trim_matches('"');
+            Some((key, value))
+        })
+        .collect()
+}
+
+// GFF3 ids carry the feature type, `gene:ENSG00000223972` or `gene-A2M`.
+fn strip_id_prefix(id: &str) -> &str {
+    if id.starts_with("gene:") || id.starts_with("gene-") {
+        &id[5..]
+    } else {
+        id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_data::test_dir;
+    use std::fs;
+
+    #[test]
+    fn test_read_tx2gene() -> Result<(), Error> {
+        let dir = test_dir("read_tx2gene");
+
+        let tsv = dir.join("tx2gene.tsv");
+        fs::write(
+            &tsv,
+            "# transcript\tgene\ntx1\tgeneA\ntx2\tgeneA\ntx3 geneB\n",
+        )?;
+        let tx2gene = read_tx2gene(&tsv)?;
+        assert_eq!(tx2gene.len(), 3);
+        assert_eq!(tx2gene["tx2"], "geneA");
+        assert_eq!(tx2gene["tx3"], "geneB");
+
+        let gtf = dir.join("tx2gene.gtf");
+        fs::write(
+            &gtf,
+            "chr1\tHAVANA\tgene\t11869\t14409\t.\t+\t.\tgene_id \"ENSG1\"; gene_name \"DDX11L1\";\n\
+             chr1\tHAVANA\ttranscript\t11869\t14409\t.\t+\t.\tgene_id \"ENSG1\"; transcript_id \"ENST1\";\n\
+             chr1\tHAVANA\texon\t11869\t12227\t.\t+\t.\tgene_id \"ENSG1\"; transcript_id \"ENST1\";\n",
+        )?;
+        let tx2gene = read_tx2gene(&gtf)?;
+        assert_eq!(tx2gene.len(), 1);
+        assert_eq!(tx2gene["ENST1"], "ENSG1");
+
+        let bad = dir.join("tx2gene_bad.tsv");
+        fs::write(&bad, "tx1\n")?;
+        assert!(read_tx2gene(&bad).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_annotation() -> Result<(), Error> {
+        let dir = test_dir("read_annotation");
+
+        // Ensembl GTF, unversioned transcript ids
+        let gtf = dir.join("annotation.gtf");
+        fs::write(
+            &gtf,
+            "#!genome-build GRCh38.p13\n\
+             1\thavana\tgene\t11869\t14409\t.\t+\t.\tgene_id \"ENSG00000223972\"; gene_version \"5\"; gene_name \"DDX11L1\"; gene_biotype \"transcribed_unprocessed_pseudogene\";\n\
+             1\thavana\ttranscript\t11869\t14409\t.\t+\t.\tgene_id \"ENSG00000223972\"; transcript_id \"ENST00000456328\"; transcript_version \"2\"; gene_name \"DDX11L1\";\n\
+             1\thavana\texon\t11869\t12227\t.\t+\t.\tgene_id \"ENSG00000223972\"; transcript_id \"ENST00000456328\"; transcript_version \"2\"; exon_number \"1\";\n",
+        )?;
+        let annotation = Annotation::read(&gtf)?;
+        assert_eq!(annotation.tx_gene_mapping.len(), 1);
+        assert_eq!(
+            annotation.tx_gene_mapping["ENST00000456328.2"],
+            "ENSG00000223972"
+        );
+        let info = &annotation.genes["ENSG00000223972"];
+        assert_eq!(info.gene_name.as_ref().unwrap(), "DDX11L1");
+        assert_eq!(
+            info.gene_biotype.as_ref().unwrap(),
+            "transcribed_unprocessed_pseudogene"
+        );
+
+        // NCBI GFF3, transcripts linked to their gene by `Parent`
+        let gff = dir.join("annotation.gff3");
+        fs::write(
+            &gff,
+            "##gff-version 3\n\
+             NC_000012.12\tBestRefSeq\tgene\t9067708\t9116229\t.\t-\t.\tID=gene-A2M;Name=A2M;gbkey=Gene;gene=A2M;gene_biotype=protein_coding\n\
+             NC_000012.12\tBestRefSeq\tmRNA\t9067708\t9116229\t.\t-\t.\tID=rna-NM_000014.6;Parent=gene-A2M;gbkey=mRNA;transcript_id=NM_000014.6\n\
+             NC_000012.12\tBestRefSeq\texon\t9116047\t9116229\t.\t-\t.\tID=exon-NM_000014.6-1;Parent=rna-NM_000014.6;transcript_id=NM_000014.6\n",
+        )?;
+        let annotation = Annotation::read(&gff)?;
+        assert_eq!(annotation.tx_gene_mapping["NM_000014.6"], "A2M");
+        assert_eq!(
+            annotation.genes["A2M"].gene_biotype.as_ref().unwrap(),
+            "protein_coding"
+        );
+
+        let tx_names = vec!["NM_000014.6".to_string(), "NM_999999.1".to_string()];
+        assert!(annotation.check_transcripts(&tx_names[..1]).is_ok());
+        let missing = annotation.check_transcripts(&tx_names).unwrap_err();
+        assert!(missing.to_string().contains("NM_999999.1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_splice_transcripts() -> Result<(), Error> {
+        let dir = test_dir("splice_transcripts");
+        let gtf = dir.join("splice.gtf");
+        fs::write(
+            &gtf,
+            "chr1\ttest\texon\t3\t6\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n\
+             chr1\ttest\texon\t11\t14\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n\
+             chr1\ttest\texon\t13\t16\t.\t-\t.\tgene_id \"g2\"; transcript_id \"t2\";\n\
+             chr1\ttest\texon\t1\t2\t.\t-\t.\tgene_id \"g2\"; transcript_id \"t2\";\n",
+        )?;
+        let annotation = Annotation::read(&gtf)?;
+        assert_eq!(annotation.transcripts[1].exons, vec![(1, 2), (13, 16)]);
+
+        let genome = b">chr1 test\nAAccGTACTTTTGGCATG\n>chr2\nACGT\n";
+        let (seqs, tx_names, tx_gene_map) =
+            annotation.splice_transcripts(fasta::Reader::new(&genome[..]))?;
+        assert_eq!(tx_names, vec!["t1", "t2"]);
+        assert_eq!(tx_gene_map["t2"], "g2");
+        assert_eq!(seqs[0].to_string(), "CCGTTTGG");
+        // AA + GGCA, reverse complemented
+        assert_eq!(seqs[1].to_string(), "TGCCTT");
+
+        let short_genome = b">chr1\nAACCGT\n";
+        assert!(annotation
+            .splice_transcripts(fasta::Reader::new(&short_genome[..]))
+            .is_err());
+
+        let mut annotation = annotation;
+        assert_eq!(annotation.add_introns(1), 2);
+        assert_eq!(annotation.tx_gene_mapping["g2-I1"], "g2");
+        let (seqs, tx_names, _) = annotation.splice_transcripts(fasta::Reader::new(&genome[..]))?;
+        assert_eq!(tx_names, vec!["t1", "t2", "g1-I1", "g2-I1"]);
+        assert_eq!(annotation.transcripts[2].kind, TargetKind::Intron);
+        // intron 7-10 with a base of flank on each side
+        assert_eq!(seqs[2].to_string(), "TACTTT");
+        assert_eq!(seqs[3].to_string(), "CAAAAGTACGGT");
+        Ok(())
+    }
+}
@@ -1,21 +1,35 @@
 // Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
 
-use log::info;
-use serde::Deserialize;
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use bio::io::{fasta, fastq};
 use docopt::Docopt;
-use failure::Error;
-use std::{env, fs};
-use std::{path::PathBuf, str};
+use failure::{format_err, Error};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fs, io, process};
 
+use debruijn::{kmer, Kmer, Mer};
+use debruijn_mapping::config::{
+    ConfigFile, IndexConfig, MappingConfig, MIN_FRAGMENT_PAIRS, SUPPORTED_KMER_SIZES,
+};
+use debruijn_mapping::error::MappingError;
+use debruijn_mapping::utils;
 use debruijn_mapping::{
+    annotation::{read_tx2gene, Annotation},
+    bootstrap,
     build_index::build_index,
+    fragment_length::FragmentLengthDist,
+    index_file::{self, transcripts_digest, write_index, IndexHeader},
+    inspect::inspect_genes,
     mappability::{analyze_graph, write_mappability_tsv},
-    pseudoaligner,
-    pseudoaligner::process_reads,
+    output::ReadOutputFormat,
+    pseudoaligner::{process_paired_reads, process_reads, MappingSummary, Pseudoaligner},
+    quant,
+    report::RunReport,
+    single_cell, velocity,
 };
-use debruijn_mapping::{config, utils};
 
 const PKG_NAME: &'static str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -23,21 +37,103 @@ const USAGE: &'static str = "
 De-bruijn-mapping
 
 Usage:
-  pseudoaligner index [--num-threads=<n>] -i <index> <ref-fasta>
-  pseudoaligner map [--num-threads=<n>] -i <index> <reads-fastq>
+  pseudoaligner index [options] -i <index> <ref-fasta>
+  pseudoaligner map [options] -i <index> <reads-fastq> [<mate-fastq>]
+  pseudoaligner map-sc [options] -i <index> <reads-fastq>
   pseudoaligner mappability [-o <outdir>] -i <index>
   pseudoaligner idxstats -i <index>
+  pseudoaligner verify -i <index>
   pseudoaligner inspect -i <index> -c <counts> <genes>...
   pseudoaligner -h | --help | -v | --version
 
 Options:
   -n --num-threads N  Number of worker threads [default: 2]
   -o --outdir DIR     Output directory
+  --config FILE       Index and mapping settings, one `name = value` per line;
+                      command line options take precedence
+  -k --kmer-size N    Length of the index kmers: 14, 16, 20, 24 or 31 (default: 20);
+                      other lengths, e.g. 15, have no kmer type in debruijn
+  --min-kmers N       Minimum observations of a kmer to keep it in the index (default: 1)
+  --tx2gene FILE      Gene of each transcript, as a TSV of transcript and gene
+                      ids or a GTF; overrides the genes in the FASTA headers
+  --gtf FILE          GTF or GFF3 annotation giving the gene, gene name and
+                      biotype of each transcript; all transcripts must be annotated
+  --genome            <ref-fasta> is a genome; index the transcripts spliced from
+                      it by the exons of the --gtf annotation
+  --introns           With --genome, also index the introns of each gene, and
+                      count spliced and unspliced reads per gene when mapping
+  --positions         Record the transcript positions of the graph nodes, to
+                      report read positions in the per-read output
+  --max-mismatches N  Mismatches tolerated when extending a match along a graph node (default: 2)
+  --kmer-stride N     Step between read kmers looked up in the index (default: 3)
+  --library-type TYPE  Orientation of the reads (of the first mate) relative to
+                      the transcripts: forward, reverse or unstranded (default: forward)
+  --coverage-threshold N  Read bases that must be covered to count a read, or each
+                      mate of a pair, as mapped (default: 32)
+  --left-extend-fraction F  Fraction of the read skipped before the first kmer hit
+                      above which the match is extended back (default: 0.2)
+  --no-mmap           Read the kmer table into memory instead of mapping it from
+                      the index file; the rest of the index is always read into memory.
+                      A mapped table isn't checked against the index checksum, see
+                      `verify`
+  --read-output FMT   Per-read output format: tsv, tsv-ids, bin or sam; sam needs an
+                      index built with --positions [default: tsv]
+  --fragment-length-mean F  Mean fragment length for the effective transcript
+                      lengths, e.g. of single-end reads; overrides the length
+                      distribution estimated from read pairs
+  --fragment-length-sd F  Standard deviation of the fragment length, with
+                      --fragment-length-mean
+  --vbem              Estimate abundances with variational Bayes EM
+  --bootstraps N      Number of bootstrap resamples of the abundance estimates,
+                      written to bootstraps.bin [default: 0]
+  --seed N            Seed of the bootstrap resampling [default: 42]
+  --gene-counts       Also write read counts per gene
+  --distribute-multigene  Split reads compatible with several genes between them
+  --barcode-fastq FILE  Single-cell barcode reads; otherwise barcode and UMI are
+                      taken from the CB/UB tags or name of each read
+  --barcode-len N     Cell barcode length in the barcode reads [default: 16]
+  --umi-len N         UMI length in the barcode reads [default: 12]
   -h --help           Show this screen.
   -v --version        Show version.
+
+FASTA and FASTQ inputs may be gzip compressed, and `-` reads from stdin.
 ";
 // -l --long         Long output format (one line per read-transcript mapping)
 
+/// Evaluate `$body` with `$K` naming the kmer type of length `$k`, one of
+/// `config::SUPPORTED_KMER_SIZES`.
+macro_rules! with_kmer_type {
+    ($k:expr, $K:ident, $body:expr) => {
+        match $k {
+            14 => {
+                type $K = kmer::Kmer14;
+                $body
+            }
+            16 => {
+                type $K = kmer::Kmer16;
+                $body
+            }
+            20 => {
+                type $K = kmer::Kmer20;
+                $body
+            }
+            24 => {
+                type $K = kmer::Kmer24;
+                $body
+            }
+            31 => {
+                type $K = kmer::Kmer31;
+                $body
+            }
+            k => Err(format_err!(
+                "unsupported kmer size {}, choose one of {:?}",
+                k,
+                SUPPORTED_KMER_SIZES
+            )),
+        }
+    };
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct Args {
     arg_ref_fasta: String,
@@ -46,81 +142,461 @@ struct Args {
     arg_locus: Option<String>,
     arg_genes: Vec<String>,
     arg_reads_fastq: String,
+    arg_mate_fastq: Option<String>,
     flag_outdir: Option<String>,
     flag_num_threads: usize,
+    flag_config: Option<String>,
+    flag_kmer_size: Option<usize>,
+    flag_min_kmers: Option<usize>,
+    flag_tx2gene: Option<String>,
+    flag_gtf: Option<String>,
+    flag_genome: bool,
+    flag_introns: bool,
+    flag_positions: bool,
+    flag_max_mismatches: Option<usize>,
+    flag_kmer_stride: Option<usize>,
+    flag_library_type: Option<String>,
+    flag_coverage_threshold: Option<usize>,
+    flag_left_extend_fraction: Option<f64>,
+    flag_no_mmap: bool,
+    flag_read_output: String,
+    flag_fragment_length_mean: Option<f64>,
+    flag_fragment_length_sd: Option<f64>,
+    flag_vbem: bool,
+    flag_bootstraps: usize,
+    flag_seed: u64,
+    flag_gene_counts: bool,
+    flag_distribute_multigene: bool,
+    flag_barcode_fastq: Option<String>,
+    flag_barcode_len: usize,
+    flag_umi_len: usize,
 
     cmd_index: bool,
 
     cmd_map: bool,
+    cmd_map_sc: bool,
     cmd_mappability: bool,
     cmd_idxstats: bool,
+    cmd_inspect: bool,
+    cmd_verify: bool,
 
     // flag_long: bool,
     flag_version: bool,
     flag_v: bool,
 }
 
-fn main() -> Result<(), Error> {
+fn main() {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
     if args.flag_version || args.flag_v {
         println! {"{} {}", PKG_NAME, PKG_VERSION};
-        return Ok(());
+        return;
     }
 
     // initialize logger
     pretty_env_logger::init_timed();
     info!("Command line args:\n{:?}", args);
 
+    if let Err(err) = run(&args) {
+        eprintln!("Error: {}", err);
+        for cause in err.iter_causes() {
+            eprintln!("  caused by: {}", cause);
+        }
+        process::exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Error> {
+    if args.flag_num_threads == 0 {
+        return Err(format_err!("--num-threads must be at least 1"));
+    }
+
+    let config_file = match &args.flag_config {
+        Some(path) => ConfigFile::read(path)?,
+        None => ConfigFile::default(),
+    };
+    let mapping_config = mapping_config(args, &config_file)?;
+
     let outdir = match &args.flag_outdir {
         Some(dir) => PathBuf::from(dir),
         None => env::current_dir()?,
     };
-    fs::create_dir_all(&outdir)?;
+    fs::create_dir_all(&outdir).map_err(|err| MappingError::io(&outdir, err))?;
 
     if args.cmd_index {
-        info!("Building index from fasta");
-        let fasta = fasta::Reader::from_file(args.arg_ref_fasta)?;
-        let (seqs, tx_names, tx_gene_map) = utils::read_transcripts(fasta)?;
-        let index =
-            build_index::<config::KmerType>(&seqs, &tx_names, &tx_gene_map, args.flag_num_threads)?;
-        info!("Finished building index!");
-
-        info!("Writing index to disk");
-        utils::write_obj(&index, args.arg_index)?;
-        info!("Finished writing index!");
-    } else if args.cmd_map {
-        info!("Reading index from disk");
-        let index = utils::read_obj(args.arg_index)?;
-        info!("Finished reading index!");
+        let index_config = index_config(args, &config_file, IndexConfig::default())?;
+        with_kmer_type!(
+            index_config.kmer_size,
+            K,
+            run_index::<K>(args, &index_config)
+        )?;
+    } else if args.cmd_verify {
+        index_file::verify_index(&args.arg_index)?;
+        println!("{}: OK", args.arg_index);
+    } else {
+        // the other commands dispatch on the kmer size the index was built with
+        let header = index_file::read_index_header(&args.arg_index)?;
+        info!(
+            "Index built by version {} from {} transcripts (digest {}): {:?}",
+            header.crate_version, header.num_transcripts, header.fasta_digest, header.index_config
+        );
+        let kmer_size = header.index_config.kmer_size;
+        with_kmer_type!(
+            kmer_size,
+            K,
+            run_with_index::<K>(args, &config_file, &mapping_config, &outdir)
+        )?;
+    }
+
+    info!("Done!");
+    Ok(())
+}
+
+fn run_index<K: Kmer + Sync + Send + Serialize>(
+    args: &Args,
+    index_config: &IndexConfig,
+) -> Result<(), Error> {
+    info!("Building index from fasta");
+    let fasta = utils::fasta_reader(&args.arg_ref_fasta)?;
+    let mut annotation = match &args.flag_gtf {
+        Some(path) => Some(Annotation::read(path)?),
+        None => None,
+    };
+    if index_config.introns {
+        match &mut annotation {
+            // the flanks hold every kmer that spans an exon-intron junction
+            Some(annotation) if args.flag_genome => annotation.add_introns(K::k() - 1),
+            _ => return Err(format_err!("--introns requires --genome and --gtf")),
+        };
+    }
+    let tx2gene = match (&args.flag_tx2gene, &annotation) {
+        (Some(_), Some(_)) => return Err(format_err!("--tx2gene and --gtf are exclusive")),
+        (Some(path), None) => Some(read_tx2gene(path)?),
+        (None, Some(annotation)) => Some(annotation.tx_gene_mapping.clone()),
+        (None, None) => None,
+    };
+    let (seqs, tx_names, tx_gene_map) = match &annotation {
+        Some(annotation) if args.flag_genome => annotation.splice_transcripts(fasta)?,
+        _ if args.flag_genome => return Err(format_err!("--genome requires a --gtf annotation")),
+        _ => utils::read_transcripts_with_tx2gene(fasta, tx2gene.as_ref())?,
+    };
+    if let Some(annotation) = &annotation {
+        annotation.check_transcripts(&tx_names)?;
+    }
+
+    let mut index = build_index::<K>(
+        &seqs,
+        &tx_names,
+        &tx_gene_map,
+        index_config,
+        args.flag_num_threads,
+    )?;
+    if let Some(annotation) = annotation {
+        index.target_kinds = annotation.target_kinds(&index.tx_names);
+        index.gene_info = annotation.genes;
+    }
+    info!("Finished building index!");
+
+    info!("Writing index to disk");
+    let header = IndexHeader::new(&index, transcripts_digest(&seqs, &tx_names));
+    write_index(&index, &header, &args.arg_index)?;
+    info!("Finished writing index!");
+    Ok(())
+}
+
+/// Fragment length distribution for the effective transcript lengths: the
+/// one given on the command line, or the one observed in the read pairs if
+/// there are enough of them. Mates are only located with an index built
+/// with `--positions`.
+fn fragment_length_dist<K: Kmer>(
+    args: &Args,
+    index: &Pseudoaligner<K>,
+    summary: &MappingSummary,
+) -> Result<Option<FragmentLengthDist>, Error> {
+    let observed = &summary.fragment_lengths;
+    match (args.flag_fragment_length_mean, args.flag_fragment_length_sd) {
+        (Some(mean), Some(sd)) => {
+            info!("Using fragment length mean {}, sd {}", mean, sd);
+            Ok(Some(FragmentLengthDist::normal(mean, sd)?))
+        }
+        // already warned about before mapping
+        (None, None) if index.node_positions.is_none() => Ok(None),
+        (None, None) if observed.total() >= MIN_FRAGMENT_PAIRS as f64 => {
+            info!(
+                "Estimated fragment length mean {:.1}, sd {:.1} from {} read pairs",
+                observed.mean(),
+                observed.sd(),
+                observed.total()
+            );
+            Ok(Some(observed.clone()))
+        }
+        (None, None) => {
+            if args.arg_mate_fastq.is_some() {
+                warn!(
+                    "Only {} read pairs with a fragment length, using the default mean \
+                     fragment length",
+                    observed.total()
+                );
+            }
+            Ok(None)
+        }
+        _ => Err(format_err!(
+            "--fragment-length-mean and --fragment-length-sd must be given together"
+        )),
+    }
+}
+
+fn run_with_index<K: Kmer + Sync + Send + DeserializeOwned>(
+    args: &Args,
+    config_file: &ConfigFile,
+    mapping_config: &MappingConfig,
+    outdir: &Path,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let start_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs());
+
+    info!("Reading index from disk");
+    let (header, index): (_, Pseudoaligner<K>) = if args.flag_no_mmap {
+        index_file::read_index(&args.arg_index)?
+    } else {
+        index_file::map_index(&args.arg_index)?
+    };
+    info!("Finished reading index!");
+
+    if args.cmd_map {
+        check_index_config(args, config_file, &index.index_config)?;
+        let read_output: ReadOutputFormat = args.flag_read_output.parse()?;
+
+        if args.arg_mate_fastq.is_some()
+            && args.flag_fragment_length_mean.is_none()
+            && index.node_positions.is_none()
+        {
+            warn!(
+                "Estimating the fragment length distribution needs an index built with \
+                 --positions; using the default mean fragment length. Rebuild the index, or \
+                 give --fragment-length-mean and --fragment-length-sd"
+            );
+        }
 
         info!("Mapping reads from fastq");
-        let reads = fastq::Reader::from_file(args.arg_reads_fastq)?;
-        process_reads::<config::KmerType, _>(reads, &index, outdir, args.flag_num_threads)?;
+        let reads = utils::fastq_reader(&args.arg_reads_fastq)?;
+        let summary = match &args.arg_mate_fastq {
+            Some(mate_fastq) => {
+                let mates = utils::fastq_reader(mate_fastq)?;
+                process_paired_reads(
+                    reads,
+                    mates,
+                    &index,
+                    mapping_config,
+                    outdir,
+                    args.flag_num_threads,
+                    read_output,
+                )?
+            }
+            None => process_reads(
+                reads,
+                &index,
+                mapping_config,
+                outdir,
+                args.flag_num_threads,
+                read_output,
+            )?,
+        };
+
+        info!(
+            "Mapped {} of {} reads",
+            summary.num_mapped, summary.num_reads
+        );
+        let fragment_lengths = fragment_length_dist(args, &index, &summary)?;
+        if summary.fragment_lengths.total() > 0.0 {
+            summary.fragment_lengths.write_tsv(outdir)?;
+        }
+        let eq_counts = &summary.eq_counts;
+
+        info!("Estimating transcript abundances");
+        let abundances =
+            quant::quantify(&index, eq_counts, fragment_lengths.as_ref(), args.flag_vbem);
+        quant::write_abundance_tsv(&index, &abundances, outdir)?;
+        quant::write_eq_counts_tsv(eq_counts, outdir)?;
+
+        if args.flag_bootstraps > 0 {
+            let est_counts = bootstrap::bootstrap(
+                eq_counts,
+                &abundances.eff_lengths,
+                args.flag_vbem,
+                args.flag_bootstraps,
+                args.flag_seed,
+                args.flag_num_threads,
+            )?;
+            bootstrap::write_bootstraps(
+                &index,
+                &abundances.eff_lengths,
+                args.flag_seed,
+                &est_counts,
+                outdir,
+            )?;
+        }
+
+        if args.flag_gene_counts {
+            info!("Aggregating read counts to genes");
+            let gene_counts =
+                quant::gene_counts(&index, eq_counts, args.flag_distribute_multigene)?;
+            quant::write_gene_counts_tsv(&gene_counts, outdir)?;
+        }
+
+        if index.index_config.introns {
+            info!("Counting spliced and unspliced reads");
+            let counts = velocity::velocity_counts(&index, eq_counts)?;
+            velocity::write_velocity_tsv(&index, &counts, outdir)?;
+        }
+
+        RunReport::new(
+            &summary,
+            &args.arg_index,
+            &header,
+            start_time,
+            start.elapsed(),
+            args.flag_num_threads,
+        )
+        .write_json(outdir)?;
+    } else if args.cmd_map_sc {
+        check_index_config(args, config_file, &index.index_config)?;
+
+        info!("Mapping single-cell reads from fastq");
+        let reads = utils::fastq_reader(&args.arg_reads_fastq)?;
+        let (summary, umi_counts) = match &args.flag_barcode_fastq {
+            Some(barcode_fastq) => {
+                let barcodes = utils::fastq_reader(barcode_fastq)?;
+                single_cell::map_barcoded_records(
+                    barcodes.records(),
+                    reads.records(),
+                    args.flag_barcode_len,
+                    args.flag_umi_len,
+                    &index,
+                    mapping_config,
+                    args.flag_num_threads,
+                )?
+            }
+            None => single_cell::map_tagged_records(
+                reads.records(),
+                args.flag_barcode_len,
+                args.flag_umi_len,
+                &index,
+                mapping_config,
+                args.flag_num_threads,
+            )?,
+        };
+        info!(
+            "Mapped {} of {} reads, {} distinct UMIs",
+            summary.num_mapped,
+            summary.num_reads,
+            umi_counts.num_umis()
+        );
+
+        info!("Counting UMIs per cell and gene");
+        let matrix = umi_counts.gene_matrix(&index)?;
+        single_cell::write_matrix_market(&matrix, &index, outdir)?;
+
+        if index.index_config.introns {
+            info!("Counting spliced and unspliced UMIs per cell and gene");
+            let matrices = umi_counts.velocity_matrices(&index)?;
+            single_cell::write_velocity_matrices(&matrices, outdir)?;
+        }
+
+        RunReport::new(
+            &summary,
+            &args.arg_index,
+            &header,
+            start_time,
+            start.elapsed(),
+            args.flag_num_threads,
+        )
+        .write_json(outdir)?;
     } else if args.cmd_mappability {
-        info!("Reading index from disk");
-        let index = debruijn_mapping::utils::read_obj(args.arg_index)?;
-        info!("Finished reading index!");
         info!("Analyzing de Bruijn graph");
-        let records = analyze_graph::<config::KmerType>(&index)?;
+        let records = analyze_graph(&index)?;
         info!("Finished analyzing!");
         info!("{} transcripts total", records.len());
         write_mappability_tsv(records, outdir)?;
     } else if args.cmd_idxstats {
-        let index: pseudoaligner::Pseudoaligner<config::KmerType> =
-            utils::read_obj(args.arg_index)?;
-
-        use debruijn::Mer;
-
         for e in index.dbg.iter_nodes() {
             let eqid = e.data();
             let eq = &index.eq_classes[*eqid as usize];
             println!("{}\t{}\t{}", e.node_id, e.sequence().len(), eq.len());
         }
+    } else if args.cmd_inspect {
+        let eq_counts = quant::read_eq_counts_tsv(&args.arg_counts)?;
+        let stdout = io::stdout();
+        inspect_genes(&index, &eq_counts, &args.arg_genes, stdout.lock())?;
     }
 
-    info!("Done!");
     Ok(())
 }
+
+/// Mapping settings: defaults, overridden by the config file, overridden by
+/// the command line.
+fn mapping_config(args: &Args, config_file: &ConfigFile) -> Result<MappingConfig, Error> {
+    let mut config = MappingConfig::default();
+    config_file.update_mapping_config(&mut config)?;
+
+    if let Some(v) = args.flag_max_mismatches {
+        config.max_mismatches = v;
+    }
+    if let Some(v) = args.flag_kmer_stride {
+        config.kmer_stride = v;
+    }
+    if let Some(v) = &args.flag_library_type {
+        config.library_type = v.parse()?;
+    }
+    if let Some(v) = args.flag_coverage_threshold {
+        config.read_coverage_threshold = v;
+    }
+    if let Some(v) = args.flag_left_extend_fraction {
+        config.left_extend_fraction = v;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Index settings: `base`, overridden by the config file, overridden by the
+/// command line.
+fn index_config(
+    args: &Args,
+    config_file: &ConfigFile,
+    base: IndexConfig,
+) -> Result<IndexConfig, Error> {
+    let mut config = base;
+    config_file.update_index_config(&mut config)?;
+
+    if let Some(v) = args.flag_kmer_size {
+        config.kmer_size = v;
+    }
+    if let Some(v) = args.flag_min_kmers {
+        config.min_kmers = v;
+    }
+    if args.flag_introns {
+        config.introns = true;
+    }
+    if args.flag_positions {
+        config.positions = true;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Make sure that index settings given when mapping agree with the
+/// settings the index was built with.
+fn check_index_config(
+    args: &Args,
+    config_file: &ConfigFile,
+    built: &IndexConfig,
+) -> Result<(), Error> {
+    let requested = index_config(args, config_file, built.clone())?;
+    built.check_compatible(&requested)
+}
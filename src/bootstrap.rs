@@ -0,0 +1,185 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Bootstrap estimates of the transcript abundances, for the technical
+//! variance used by differential expression tools. Each bootstrap draws as
+//! many reads as were mapped from the equivalence class counts, with
+//! replacement, and reruns the abundance estimation on them.
+use std::path::Path;
+
+use debruijn::Kmer;
+use failure::Error;
+use log::info;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::VBEM_PRIOR;
+use crate::pseudoaligner::Pseudoaligner;
+use crate::quant::{em, EqClassCounts};
+use crate::utils::{read_obj, write_obj};
+
+/// Contents of `bootstraps.bin`, a bincode-encoded record of the estimated
+/// counts of every bootstrap, next to the transcripts and effective lengths
+/// they refer to.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BootstrapCounts {
+    pub tx_names: Vec<String>,
+    pub eff_lengths: Vec<f64>,
+    pub seed: u64,
+    // estimated counts per bootstrap, indexed by transcript
+    pub est_counts: Vec<Vec<f32>>,
+}
+
+/// Run `num_bootstraps` resamples of `eq_counts` through the EM on
+/// `num_threads` threads. Bootstrap `i` uses a generator seeded from `seed`
+/// and `i`, so the results don't depend on the thread count.
+pub fn bootstrap(
+    eq_counts: &EqClassCounts,
+    eff_lengths: &[f64],
+    vbem: bool,
+    num_bootstraps: usize,
+    seed: u64,
+    num_threads: usize,
+) -> Result<Vec<Vec<f64>>, Error> {
+    // fixed class order, so that a seed always gives the same resamples
+    let mut classes: Vec<(&Vec<u32>, u32)> = eq_counts
+        .iter()
+        .filter(|&(_, &n)| n > 0)
+        .map(|(c, &n)| (c, n))
+        .collect();
+    classes.sort();
+    let mut cum_counts = Vec::with_capacity(classes.len());
+    let mut total = 0u64;
+    for &(_, count) in &classes {
+        total += u64::from(count);
+        cum_counts.push(total);
+    }
+
+    let vbem_prior = if vbem { Some(VBEM_PRIOR) } else { None };
+    info!(
+        "Running {} bootstraps of {} reads on {} threads",
+        num_bootstraps, total, num_threads
+    );
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()?;
+    let est_counts = pool.install(|| {
+        (0..num_bootstraps)
+            .into_par_iter()
+            .map(|i| {
+                let mut rng = SplitMix64::new(seed ^ (i as u64).wrapping_mul(GOLDEN_GAMMA));
+                let mut counts = vec![0u32; classes.len()];
+                for _ in 0..total {
+                    let r = rng.below(total);
+                    // first class whose cumulative count exceeds r
+                    let c = match cum_counts.binary_search(&r) {
+                        Ok(c) => c + 1,
+                        Err(c) => c,
+                    };
+                    counts[c] += 1;
+                }
+
+                let resample: EqClassCounts = classes
+                    .iter()
+                    .zip(counts)
+                    .filter(|&(_, count)| count > 0)
+                    .map(|(&(eq_class, _), count)| (eq_class.clone(), count))
+                    .collect();
+                em(&resample, eff_lengths, vbem_prior)
+            })
+            .collect()
+    });
+
+    Ok(est_counts)
+}
+
+/// Write the bootstrap counts to `bootstraps.bin` in `outdir`.
+pub fn write_bootstraps<K: Kmer, P: AsRef<Path>>(
+    index: &Pseudoaligner<K>,
+    eff_lengths: &[f64],
+    seed: u64,
+    est_counts: &[Vec<f64>],
+    outdir: P,
+) -> Result<(), Error> {
+    let bootstraps = BootstrapCounts {
+        tx_names: index.tx_names.clone(),
+        eff_lengths: eff_lengths.to_vec(),
+        seed,
+        est_counts: est_counts
+            .iter()
+            .map(|counts| counts.iter().map(|&c| c as f32).collect())
+            .collect(),
+    };
+    write_obj(&bootstraps, outdir.as_ref().join("bootstraps.bin"))
+}
+
+/// Load a file written by `write_bootstraps`.
+pub fn read_bootstraps<P: AsRef<Path>>(filename: P) -> Result<BootstrapCounts, Error> {
+    read_obj(filename.as_ref())
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// SplitMix64 generator; small and fast, and plenty for resampling.
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform draw from `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        ((u128::from(self.next_u64()) * u128::from(n)) >> 64) as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn counts(classes: &[(&[u32], u32)]) -> EqClassCounts {
+        classes.iter().map(|&(c, n)| (c.to_vec(), n)).collect()
+    }
+
+    #[test]
+    fn test_bootstrap() -> Result<(), Error> {
+        let eq_counts = counts(&[(&[0], 100), (&[1], 300), (&[0, 1], 100)]);
+        let eff_lengths = [100.0, 100.0, 100.0];
+
+        let boots = bootstrap(&eq_counts, &eff_lengths, false, 4, 7, 2)?;
+        assert_eq!(boots.len(), 4);
+        for est_counts in &boots {
+            // every read is resampled, and the absent transcript stays empty
+            assert!((est_counts.iter().sum::<f64>() - 500.0).abs() < 1e-6);
+            assert_eq!(est_counts[2], 0.0);
+        }
+        assert_ne!(boots[0], boots[1]);
+
+        // same seed, same bootstraps, whatever the thread count
+        assert_eq!(bootstrap(&eq_counts, &eff_lengths, false, 4, 7, 1)?, boots);
+        Ok(())
+    }
+
+    #[test]
+    fn test_below() {
+        let mut rng = SplitMix64::new(1);
+        let mut seen = [false; 5];
+        for _ in 0..1000 {
+            let r = rng.below(5);
+            assert!(r < 5);
+            seen[r as usize] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+}
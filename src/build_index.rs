@@ -4,8 +4,8 @@ use lazy_static::lazy_static;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-use crate::config::{KmerType, MEM_SIZE, REPORT_ALL_KMER, STRANDED};
-use boomphf::hashmap::{BoomHashMap2, NoKeyBoomHashMap};
+use crate::config::{IndexConfig, MappingConfig};
+use boomphf::hashmap::BoomHashMap2;
 use debruijn;
 use debruijn::compression::*;
 use debruijn::dna_string::{DnaString, DnaStringSlice};
@@ -13,11 +13,12 @@ use debruijn::filter::*;
 use debruijn::graph::*;
 use debruijn::*;
 
-use crate::config::{MIN_KMERS, U32_MAX};
+use crate::config::{STRANDED, U32_MAX};
 use crate::equiv_classes::{CountFilterEqClass, EqClassIdType};
-use crate::pseudoaligner::Pseudoaligner;
+use crate::kmer_index::KmerIndex;
+use crate::pseudoaligner::{NodeTxPosition, Pseudoaligner, Strand};
 use boomphf;
-use failure::Error;
+use failure::{format_err, Error};
 use log::info;
 use rayon::prelude::*;
 use rayon::{self, ThreadPool};
@@ -28,15 +29,28 @@ pub fn build_index<K: Kmer + Sync + Send>(
     seqs: &[DnaString],
     tx_names: &Vec<String>,
     tx_gene_map: &HashMap<String, String>,
+    config: &IndexConfig,
     num_threads: usize,
 ) -> Result<Pseudoaligner<K>, Error> {
+    config.validate()?;
+    if config.kmer_size != K::k() {
+        return Err(format_err!(
+            "index config asks for kmer size {}, but the index is built with k = {}",
+            config.kmer_size,
+            K::k()
+        ));
+    }
+
     // Thread pool Configuration for calling BOOMphf
     let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(num_threads)
         .build()?;
 
     if seqs.len() >= U32_MAX {
-        panic!("Too many ({}) sequences to handle.", seqs.len());
+        return Err(format_err!(
+            "Too many ({}) sequences to handle.",
+            seqs.len()
+        ));
     }
 
     info!("Sharding sequences...");
@@ -44,7 +58,7 @@ pub fn build_index<K: Kmer + Sync + Send>(
     let mut buckets: Vec<_> = seqs
         .iter()
         .enumerate()
-        .flat_map(|(id, seq)| partition_contigs::<KmerType>(seq, id as u32))
+        .flat_map(|(id, seq)| partition_contigs::<K>(seq, id as u32, STRANDED))
         .collect();
 
     pool.install(|| {
@@ -52,7 +66,7 @@ pub fn build_index<K: Kmer + Sync + Send>(
     });
     info!("Got {} sequence chunks", buckets.len());
 
-    let summarizer = Arc::new(CountFilterEqClass::new(MIN_KMERS));
+    let summarizer = Arc::new(CountFilterEqClass::new(config.min_kmers));
     let sequence_shards = group_by_slices(&buckets, |x| x.0, MIN_SHARD_SEQUENCES);
 
     info!("Assembling {} shards...", sequence_shards.len());
@@ -63,7 +77,7 @@ pub fn build_index<K: Kmer + Sync + Send>(
             .into_par_iter()
             .into_par_iter()
             .map_with(summarizer.clone(), |s, strings| {
-                assemble_shard::<K>(strings, s)
+                assemble_shard::<K>(strings, s, config)
             })
             .collect_into_vec(&mut shard_dbgs);
 
@@ -73,7 +87,7 @@ pub fn build_index<K: Kmer + Sync + Send>(
     info!("Done dBG construction of shards");
     info!("Starting merging disjoint graphs");
 
-    let dbg = merge_shard_dbgs(shard_dbgs);
+    let dbg = merge_shard_dbgs(shard_dbgs, STRANDED);
     info!("Graph merge complete");
 
     let eq_classes = summarizer.get_eq_classes();
@@ -81,13 +95,75 @@ pub fn build_index<K: Kmer + Sync + Send>(
     info!("Indexing de Bruijn graph");
     let dbg_index = make_dbg_index(&dbg, &pool, num_threads);
 
-    Ok(Pseudoaligner::new(
+    let tx_lengths = seqs.iter().map(|seq| seq.len()).collect();
+
+    let mut index = Pseudoaligner::new(
         dbg,
         eq_classes,
         dbg_index,
         tx_names.clone(),
+        tx_lengths,
         tx_gene_map.clone(),
-    ))
+        config.clone(),
+    );
+
+    if config.positions {
+        info!("Recording node positions in transcripts");
+        index.node_positions = Some(node_tx_positions(seqs, &index, &pool));
+    }
+
+    Ok(index)
+}
+
+/// Where each graph node occurs in each transcript, found by looking up every
+/// transcript kmer, or its reverse complement, in the graph. Only the first
+/// occurrence of a node in a transcript is kept.
+fn node_tx_positions<K: Kmer + Sync + Send>(
+    seqs: &[DnaString],
+    index: &Pseudoaligner<K>,
+    pool: &ThreadPool,
+) -> Vec<Vec<NodeTxPosition>> {
+    let tx_hits: Vec<Vec<(usize, NodeTxPosition)>> = pool.install(|| {
+        seqs.par_iter()
+            .enumerate()
+            .map(|(tx_id, seq)| {
+                let mut hits = Vec::new();
+                let mut seen = HashSet::new();
+                for (pos, kmer) in seq.iter_kmers::<K>().enumerate() {
+                    let hit = index
+                        .find_kmer(&kmer)
+                        .map(|hit| (hit, Strand::Forward))
+                        .or_else(|| {
+                            index
+                                .find_kmer(&kmer.rc())
+                                .map(|hit| (hit, Strand::Reverse))
+                        });
+
+                    if let Some(((node_id, offset), strand)) = hit {
+                        if seen.insert(node_id) {
+                            let anchor = match strand {
+                                Strand::Forward => pos as i32 - offset as i32,
+                                Strand::Reverse => pos as i32 + offset as i32,
+                            };
+                            let position = NodeTxPosition {
+                                tx_id: tx_id as u32,
+                                anchor,
+                                strand,
+                            };
+                            hits.push((node_id, position));
+                        }
+                    }
+                }
+                hits
+            })
+            .collect()
+    });
+
+    let mut node_positions = vec![Vec::new(); index.dbg.len()];
+    for (node_id, position) in tx_hits.into_iter().flatten() {
+        node_positions[node_id].push(position);
+    }
+    node_positions
 }
 
 // Manually compute the equivalence class of each kmer, and make sure
@@ -114,7 +190,7 @@ pub fn validate_dbg<K: Kmer + Sync + Send>(seqs: &[DnaString], al: &Pseudoaligne
 
         let (node_id, _) = al.dbg_index.get(&k).unwrap();
 
-        let eq_class = al.dbg.get_node(*node_id as usize).data();
+        let eq_class = al.dbg.get_node(node_id as usize).data();
         let dbg_eqclass = &al.eq_classes[*eq_class as usize];
 
         let mut dbg_eq_clone = dbg_eqclass.clone();
@@ -132,6 +208,7 @@ pub fn validate_dbg<K: Kmer + Sync + Send>(seqs: &[DnaString], al: &Pseudoaligne
     }
 
     // check that each read sequence aligns cleanly
+    let config = MappingConfig::default();
     for (i, s) in seqs.iter().enumerate() {
         let i = i as u32;
 
@@ -140,7 +217,7 @@ pub fn validate_dbg<K: Kmer + Sync + Send>(seqs: &[DnaString], al: &Pseudoaligne
             continue;
         }
 
-        let (eqclass, bases_aligned) = al.map_read(s).unwrap();
+        let (eqclass, bases_aligned) = al.map_read(s, &config).unwrap();
         assert_eq!(s.len(), bases_aligned);
 
         if eqclass.len() > 1 {
@@ -164,7 +241,7 @@ pub fn validate_dbg<K: Kmer + Sync + Send>(seqs: &[DnaString], al: &Pseudoaligne
                 let mut path_buf: Vec<usize> = Vec::new();
 
                 use std::iter::FromIterator;
-                al.map_read_to_nodes(s, &mut path_buf).unwrap();
+                al.map_read_to_nodes(s, &config, &mut path_buf).unwrap();
                 let my_nodes: HashSet<usize> = HashSet::from_iter(path_buf.iter().cloned());
 
                 println!("eqclass: {:?}", eqclass);
@@ -178,7 +255,7 @@ pub fn validate_dbg<K: Kmer + Sync + Send>(seqs: &[DnaString], al: &Pseudoaligne
                     println!("{:?}", seqs[*i as usize]);
 
                     let r = al
-                        .map_read_to_nodes(&seqs[*i as usize], &mut path_buf)
+                        .map_read_to_nodes(&seqs[*i as usize], &config, &mut path_buf)
                         .unwrap();
                     let other_nodes = HashSet::from_iter(path_buf.iter().cloned());
 
@@ -239,6 +316,7 @@ fn count_a_t_bases<K: Kmer>(kmer: &K) -> usize {
 fn partition_contigs<'a, K: Kmer>(
     contig: &'a DnaString,
     contig_id: u32,
+    stranded: bool,
 ) -> Vec<(u16, u32, DnaStringSlice<'a>, Exts)> {
     // One FASTA entry possibly broken into multiple contigs
     // based on the location of `N` int he sequence.
@@ -248,8 +326,8 @@ fn partition_contigs<'a, K: Kmer>(
     if contig.len() >= K::k() {
         // It is safe to always set rc to true when calling simple_scan. See
         // https://github.com/10XGenomics/rust-debruijn/issues/10
-        // However, we set it to !STRANDED so stranded assays use more buckets.
-        let msps = debruijn::msp::simple_scan::<_, PmerType>(K::k(), contig, &PERM, !STRANDED);
+        // However, we set it to !stranded so stranded assays use more buckets.
+        let msps = debruijn::msp::simple_scan::<_, PmerType>(K::k(), contig, &PERM, !stranded);
         for msp in msps {
             let bucket_id = msp.bucket();
             let slice = contig.slice(msp.start(), msp.end());
@@ -264,6 +342,7 @@ fn partition_contigs<'a, K: Kmer>(
 fn assemble_shard<K: Kmer>(
     shard_data: &[(u16, u32, DnaStringSlice, Exts)],
     summarizer: &Arc<CountFilterEqClass<u32>>,
+    config: &IndexConfig,
 ) -> BaseGraph<K, EqClassIdType> {
     let filter_input: Vec<_> = shard_data
         .into_iter()
@@ -275,8 +354,8 @@ fn assemble_shard<K: Kmer>(
         &filter_input,
         summarizer,
         STRANDED,
-        REPORT_ALL_KMER,
-        MEM_SIZE,
+        config.report_all_kmer,
+        config.mem_size,
     );
 
     compress_kmers_with_hash(STRANDED, &ScmapCompress::new(), &phf)
@@ -284,9 +363,10 @@ fn assemble_shard<K: Kmer>(
 
 fn merge_shard_dbgs<K: Kmer + Sync + Send>(
     uncompressed_dbgs: Vec<BaseGraph<K, EqClassIdType>>,
+    stranded: bool,
 ) -> DebruijnGraph<K, EqClassIdType> {
     let combined_graph = BaseGraph::combine(uncompressed_dbgs.into_iter()).finish();
-    compress_graph(STRANDED, &ScmapCompress::new(), combined_graph, None)
+    compress_graph(stranded, &ScmapCompress::new(), combined_graph, None)
 }
 
 #[inline(never)]
@@ -294,7 +374,7 @@ fn make_dbg_index<K: Kmer + Sync + Send>(
     dbg: &DebruijnGraph<K, EqClassIdType>,
     pool: &ThreadPool,
     num_threads: usize,
-) -> NoKeyBoomHashMap<K, (u32, u32)> {
+) -> KmerIndex<K> {
     let mut total_kmers = 0;
     let kmer_length = K::k();
     for node in dbg.iter_nodes() {
@@ -328,7 +408,7 @@ fn make_dbg_index<K: Kmer + Sync + Send>(
         );
     });
 
-    boomphf::hashmap::NoKeyBoomHashMap::new_with_mphf(mphf, node_and_offsets)
+    KmerIndex::new(mphf, node_and_offsets)
 }
 
 /// Split the slice `data` into subslices of size at least
@@ -393,7 +473,13 @@ mod test {
     fn test_gencode_small_build() -> Result<(), Error> {
         let fasta = fasta::Reader::from_file("test/gencode_small.fa")?;
         let (seqs, tx_names, tx_gene_map) = utils::read_transcripts(fasta)?;
-        let index = build_index::<config::KmerType>(&seqs, &tx_names, &tx_gene_map, 2)?;
+        let index = build_index::<config::KmerType>(
+            &seqs,
+            &tx_names,
+            &tx_gene_map,
+            &IndexConfig::default(),
+            2,
+        )?;
         validate_dbg(&seqs, &index);
         Ok(())
     }
@@ -403,7 +489,13 @@ mod test {
         let msg = "For full txome indexing test, download from ftp://ftp.ebi.ac.uk/pub/databases/gencode/Gencode_human/release_28/gencode.v28.transcripts.fa.gz, un-gzip and place in test/gencode.v28.transcripts.fa";
         let fasta = fasta::Reader::from_file("test/gencode.v28.transcripts.fa").context(msg)?;
         let (seqs, tx_names, tx_gene_map) = utils::read_transcripts(fasta)?;
-        let index = build_index::<config::KmerType>(&seqs, &tx_names, &tx_gene_map, 2)?;
+        let index = build_index::<config::KmerType>(
+            &seqs,
+            &tx_names,
+            &tx_gene_map,
+            &IndexConfig::default(),
+            2,
+        )?;
         validate_dbg(&seqs, &index);
         Ok(())
     }
@@ -1,26 +1,422 @@
 // Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
 
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
 use debruijn::kmer;
+use failure::{format_err, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::error::MappingError;
 
 // transcriptome fasta header formats
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum FastaFormat {
     Unknown,
     Gencode,
     Ensembl,
     Gffread,
+    // NCBI RefSeq, gene symbol in parentheses in the description
+    Refseq,
+    // bare transcript ids; each transcript is its own gene
+    Plain,
 }
 
 // main configs
-pub const MEM_SIZE: usize = 1;
-pub const MIN_KMERS: usize = 1;
+pub const DEFAULT_MEM_SIZE: usize = 1;
+pub const DEFAULT_MIN_KMERS: usize = 1;
+pub const DEFAULT_REPORT_ALL_KMER: bool = false;
+pub const DEFAULT_INTRONS: bool = false;
+pub const DEFAULT_POSITIONS: bool = false;
+pub const DEFAULT_READ_COVERAGE_THRESHOLD: usize = 32;
+pub const DEFAULT_LEFT_EXTEND_FRACTION: f64 = 0.2;
+pub const DEFAULT_MAX_MISMATCHES: usize = 2;
+pub const DEFAULT_KMER_STRIDE: usize = 3;
+// must match the length of `KmerType`
+pub const DEFAULT_KMER_SIZE: usize = 20;
+// kmer lengths an index can be built with: the lengths debruijn has a kmer
+// type for, so odd lengths like 15 aren't available
+pub const SUPPORTED_KMER_SIZES: &[usize] = &[14, 16, 20, 24, 31];
+
+// the graph is always strand specific: reads are looked up by their forward
+// kmers only. Unstranded libraries are mapped with `LibraryType::Unstranded`
 pub const STRANDED: bool = true;
-pub const REPORT_ALL_KMER: bool = false;
-pub const READ_COVERAGE_THRESHOLD: usize = 32;
-pub const LEFT_EXTEND_FRACTION: f64 = 0.2;
 
 pub const U32_MAX: usize = u32::max_value() as usize;
 
 pub type KmerType = kmer::Kmer20;
 
+/// Parameters of the index construction. They are stored in the index,
+/// so that mapping can check that it runs against a compatible index.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexConfig {
+    // length of the kmers of the graph
+    pub kmer_size: usize,
+    // minimum number of observations of a kmer to keep it in the graph
+    pub min_kmers: usize,
+    pub report_all_kmer: bool,
+    // add the introns of the annotated genes as targets, see `velocity`
+    pub introns: bool,
+    // record where each graph node occurs in each transcript, to report read
+    // positions when mapping
+    pub positions: bool,
+    // memory budget of the kmer filtering, in GB
+    pub mem_size: usize,
+}
+
+impl Default for IndexConfig {
+    fn default() -> IndexConfig {
+        IndexConfig {
+            kmer_size: DEFAULT_KMER_SIZE,
+            min_kmers: DEFAULT_MIN_KMERS,
+            report_all_kmer: DEFAULT_REPORT_ALL_KMER,
+            introns: DEFAULT_INTRONS,
+            positions: DEFAULT_POSITIONS,
+            mem_size: DEFAULT_MEM_SIZE,
+        }
+    }
+}
+
+impl IndexConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !SUPPORTED_KMER_SIZES.contains(&self.kmer_size) {
+            return Err(format_err!(
+                "unsupported kmer size {}, choose one of {:?}",
+                self.kmer_size,
+                SUPPORTED_KMER_SIZES
+            ));
+        }
+        if self.min_kmers == 0 {
+            return Err(format_err!("min kmers must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Check that an index built with `self` can be used where `requested`
+    /// index settings are expected, listing the settings that differ.
+    pub fn check_compatible(&self, requested: &IndexConfig) -> Result<(), Error> {
+        let mut mismatches = Vec::new();
+        if self.kmer_size != requested.kmer_size {
+            mismatches.push(format!(
+                "kmer_size: index {}, requested {}",
+                self.kmer_size, requested.kmer_size
+            ));
+        }
+        if self.min_kmers != requested.min_kmers {
+            mismatches.push(format!(
+                "min_kmers: index {}, requested {}",
+                self.min_kmers, requested.min_kmers
+            ));
+        }
+        if self.report_all_kmer != requested.report_all_kmer {
+            mismatches.push(format!(
+                "report_all_kmer: index {}, requested {}",
+                self.report_all_kmer, requested.report_all_kmer
+            ));
+        }
+        if self.introns != requested.introns {
+            mismatches.push(format!(
+                "introns: index {}, requested {}",
+                self.introns, requested.introns
+            ));
+        }
+        if self.positions != requested.positions {
+            mismatches.push(format!(
+                "positions: index {}, requested {}",
+                self.positions, requested.positions
+            ));
+        }
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(MappingError::IncompatibleIndex(format!(
+                "index was built with incompatible settings ({})",
+                mismatches.join("; ")
+            ))
+            .into())
+        }
+    }
+}
+
+/// Orientation of the reads, or of the first mate of read pairs, relative to
+/// the transcripts they come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LibraryType {
+    // reads match the transcript sequence
+    Forward,
+    // reads match the reverse complement, e.g. dUTP stranded libraries
+    Reverse,
+    // either orientation, the better mapping one is kept
+    Unstranded,
+}
+
+impl Default for LibraryType {
+    fn default() -> LibraryType {
+        LibraryType::Forward
+    }
+}
+
+impl FromStr for LibraryType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LibraryType, Error> {
+        match s {
+            "forward" => Ok(LibraryType::Forward),
+            "reverse" => Ok(LibraryType::Reverse),
+            "unstranded" => Ok(LibraryType::Unstranded),
+            _ => Err(format_err!("unknown library type: {}", s)),
+        }
+    }
+}
+
+/// Runtime parameters of the read mapping.
+#[derive(Clone, Debug)]
+pub struct MappingConfig {
+    // mismatches tolerated while extending a match along one graph node
+    pub max_mismatches: usize,
+    // step between the read kmers looked up in the index, at least 1
+    pub kmer_stride: usize,
+    // minimum number of read bases covered for a read to count as mapped
+    pub read_coverage_threshold: usize,
+    // fraction of the read that has to be skipped before the first kmer
+    // match for the mapping to be extended back to the read start
+    pub left_extend_fraction: f64,
+    // read orientations to try
+    pub library_type: LibraryType,
+}
+
+impl Default for MappingConfig {
+    fn default() -> MappingConfig {
+        MappingConfig {
+            max_mismatches: DEFAULT_MAX_MISMATCHES,
+            kmer_stride: DEFAULT_KMER_STRIDE,
+            read_coverage_threshold: DEFAULT_READ_COVERAGE_THRESHOLD,
+            left_extend_fraction: DEFAULT_LEFT_EXTEND_FRACTION,
+            library_type: LibraryType::default(),
+        }
+    }
+}
+
+impl MappingConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.kmer_stride == 0 {
+            return Err(format_err!("kmer stride must be at least 1"));
+        }
+        if !(0.0..=1.0).contains(&self.left_extend_fraction) {
+            return Err(format_err!(
+                "left extend fraction must be between 0 and 1, got {}",
+                self.left_extend_fraction
+            ));
+        }
+        Ok(())
+    }
+}
+
+const INDEX_SETTINGS: &[&str] = &[
+    "kmer_size",
+    "min_kmers",
+    "report_all_kmer",
+    "introns",
+    "positions",
+    "mem_size",
+];
+const MAPPING_SETTINGS: &[&str] = &[
+    "max_mismatches",
+    "kmer_stride",
+    "read_coverage_threshold",
+    "left_extend_fraction",
+    "library_type",
+];
+
+/// Index and mapping settings read from a config file with one
+/// `name = value` setting per line. Blank lines and `#` comments are
+/// skipped, and the names are the `IndexConfig` and `MappingConfig` fields.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigFile {
+    settings: HashMap<String, String>,
+}
+
+impl ConfigFile {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<ConfigFile, Error> {
+        let text = fs::read_to_string(&path)
+            .with_context(|_| format!("reading config file {:?}", path.as_ref()))?;
+        text.parse()
+    }
+
+    /// Overwrite the index settings present in the file.
+    pub fn update_index_config(&self, config: &mut IndexConfig) -> Result<(), Error> {
+        if let Some(v) = self.get("kmer_size")? {
+            config.kmer_size = v;
+        }
+        if let Some(v) = self.get("min_kmers")? {
+            config.min_kmers = v;
+        }
+        if let Some(v) = self.get("report_all_kmer")? {
+            config.report_all_kmer = v;
+        }
+        if let Some(v) = self.get("introns")? {
+            config.introns = v;
+        }
+        if let Some(v) = self.get("positions")? {
+            config.positions = v;
+        }
+        if let Some(v) = self.get("mem_size")? {
+            config.mem_size = v;
+        }
+        Ok(())
+    }
+
+    /// Overwrite the mapping settings present in the file.
+    pub fn update_mapping_config(&self, config: &mut MappingConfig) -> Result<(), Error> {
+        if let Some(v) = self.get("max_mismatches")? {
+            config.max_mismatches = v;
+        }
+        if let Some(v) = self.get("kmer_stride")? {
+            config.kmer_stride = v;
+        }
+        if let Some(v) = self.get("read_coverage_threshold")? {
+            config.read_coverage_threshold = v;
+        }
+        if let Some(v) = self.get("left_extend_fraction")? {
+            config.left_extend_fraction = v;
+        }
+        if let Some(v) = self.get("library_type")? {
+            config.library_type = v;
+        }
+        Ok(())
+    }
+
+    fn get<T>(&self, name: &str) -> Result<Option<T>, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.settings.get(name) {
+            None => Ok(None),
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|e| format_err!("invalid value '{}' for {}: {}", value, name, e)),
+        }
+    }
+}
+
+impl FromStr for ConfigFile {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<ConfigFile, Error> {
+        let mut settings = HashMap::new();
+
+        for (line_num, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.splitn(2, '=');
+            let name = fields.next().unwrap_or("").trim();
+            let value = match fields.next() {
+                Some(value) => value.trim(),
+                None => {
+                    return Err(format_err!(
+                        "config line {}: expected 'name = value', got '{}'",
+                        line_num + 1,
+                        line
+                    ))
+                }
+            };
+
+            if !INDEX_SETTINGS.contains(&name) && !MAPPING_SETTINGS.contains(&name) {
+                return Err(format_err!(
+                    "config line {}: unknown setting '{}'",
+                    line_num + 1,
+                    name
+                ));
+            }
+            settings.insert(name.to_string(), value.to_string());
+        }
+
+        Ok(ConfigFile { settings })
+    }
+}
+
 // Transcriptome mappability
 pub const MAPPABILITY_COUNTS_LEN: usize = 11;
+
+// Abundance estimation
+pub const MEAN_FRAGMENT_LENGTH: f64 = 200.0;
+// fragment lengths counted in the fragment length distribution
+pub const MAX_FRAGMENT_LENGTH: usize = 1000;
+// read pairs needed to use the observed fragment length distribution
+pub const MIN_FRAGMENT_PAIRS: usize = 1000;
+pub const EM_MIN_ROUNDS: usize = 50;
+pub const EM_MAX_ROUNDS: usize = 10_000;
+pub const EM_TOLERANCE: f64 = 1e-2;
+pub const EM_ALPHA_LIMIT: f64 = 1e-7;
+pub const VBEM_PRIOR: f64 = 0.01;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_file() -> Result<(), Error> {
+        let text = "# test settings\nkmer_size = 24\nmin_kmers = 2\nreport_all_kmer=true\n\nkmer_stride = 1 # every kmer\nleft_extend_fraction = 0.5\nlibrary_type = reverse\n";
+        let file: ConfigFile = text.parse()?;
+
+        let mut index_config = IndexConfig::default();
+        file.update_index_config(&mut index_config)?;
+        assert_eq!(index_config.kmer_size, 24);
+        assert_eq!(index_config.min_kmers, 2);
+        assert!(index_config.report_all_kmer);
+        assert_eq!(index_config.mem_size, DEFAULT_MEM_SIZE);
+
+        let mut mapping_config = MappingConfig::default();
+        file.update_mapping_config(&mut mapping_config)?;
+        assert_eq!(mapping_config.kmer_stride, 1);
+        assert_eq!(mapping_config.left_extend_fraction, 0.5);
+        assert_eq!(mapping_config.library_type, LibraryType::Reverse);
+        assert_eq!(mapping_config.max_mismatches, DEFAULT_MAX_MISMATCHES);
+
+        assert!("min_kmer = 2".parse::<ConfigFile>().is_err());
+        assert!("min_kmers 2".parse::<ConfigFile>().is_err());
+        assert!("stranded = false".parse::<ConfigFile>().is_err());
+        let bad_value: ConfigFile = "report_all_kmer = maybe".parse()?;
+        assert!(bad_value
+            .update_index_config(&mut IndexConfig::default())
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_config_compatibility() {
+        let built = IndexConfig::default();
+        assert!(built.check_compatible(&IndexConfig::default()).is_ok());
+
+        let mut requested = IndexConfig {
+            mem_size: 4,
+            ..IndexConfig::default()
+        };
+        assert!(built.check_compatible(&requested).is_ok());
+
+        requested.report_all_kmer = true;
+        assert!(built.check_compatible(&requested).is_err());
+
+        let requested = IndexConfig {
+            kmer_size: 31,
+            ..IndexConfig::default()
+        };
+        assert!(requested.validate().is_ok());
+        assert!(built.check_compatible(&requested).is_err());
+        assert!(IndexConfig {
+            kmer_size: 19,
+            ..IndexConfig::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Error kinds raised by the crate. Functions return `failure::Error`; the
+//! underlying `MappingError` can be recovered with `Error::downcast_ref` to
+//! tell a bad input file from other failures.
+use std::fmt::Debug;
+use std::io;
+
+use failure::Fail;
+
+#[derive(Debug, Fail)]
+pub enum MappingError {
+    // a file couldn't be opened, created, read or written
+    #[fail(display = "{}: {}", path, error)]
+    Io {
+        path: String,
+        #[cause]
+        error: io::Error,
+    },
+    // malformed input records or files
+    #[fail(display = "parse error: {}", _0)]
+    Parse(String),
+    // FASTA headers in a layout that can't be parsed into transcript and gene ids
+    #[fail(display = "unrecognized FASTA header format: {}", _0)]
+    FastaFormat(String),
+    // transcripts or genes missing from an annotation
+    #[fail(display = "annotation error: {}", _0)]
+    Annotation(String),
+    // an index that can't be used with this build or these settings
+    #[fail(display = "incompatible index: {}", _0)]
+    IncompatibleIndex(String),
+}
+
+impl MappingError {
+    pub fn io<P: Debug>(path: P, error: io::Error) -> MappingError {
+        MappingError::Io {
+            path: format!("{:?}", path),
+            error,
+        }
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Fragment length distribution, for the effective transcript lengths of the
+//! abundance estimation. It is estimated from read pairs that map to a single
+//! transcript with both mates located, which needs an index built with node
+//! positions, or given as a mean and standard deviation for single-end reads.
+use std::io::Write;
+use std::path::Path;
+
+use failure::{format_err, Error};
+
+use crate::config::MAX_FRAGMENT_LENGTH;
+use crate::utils::open_file;
+
+const FRAGMENT_LENGTHS_HEADER_STRING: &str = "length\tcount\n";
+
+/// Weight of each fragment length up to `MAX_FRAGMENT_LENGTH`; longer
+/// fragments are not counted.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FragmentLengthDist {
+    // indexed by fragment length
+    weights: Vec<f64>,
+}
+
+impl FragmentLengthDist {
+    /// Discretized normal distribution over lengths 1 to
+    /// `MAX_FRAGMENT_LENGTH`.
+    pub fn normal(mean: f64, sd: f64) -> Result<FragmentLengthDist, Error> {
+        if !(1.0..=MAX_FRAGMENT_LENGTH as f64).contains(&mean) {
+            return Err(format_err!(
+                "fragment length mean must be between 1 and {}, got {}",
+                MAX_FRAGMENT_LENGTH,
+                mean
+            ));
+        }
+        if sd.is_nan() || sd <= 0.0 {
+            return Err(format_err!(
+                "fragment length standard deviation must be positive, got {}",
+                sd
+            ));
+        }
+
+        let mut weights = vec![0.0; MAX_FRAGMENT_LENGTH + 1];
+        for (len, weight) in weights.iter_mut().enumerate().skip(1) {
+            let z = (len as f64 - mean) / sd;
+            *weight = (-0.5 * z * z).exp();
+        }
+        Ok(FragmentLengthDist { weights })
+    }
+
+    /// Count a fragment of `len` bases.
+    pub fn add(&mut self, len: u32) {
+        let len = len as usize;
+        if len == 0 || len > MAX_FRAGMENT_LENGTH {
+            return;
+        }
+        if self.weights.len() <= len {
+            self.weights.resize(len + 1, 0.0);
+        }
+        self.weights[len] += 1.0;
+    }
+
+    /// Total weight, i.e. the number of fragments counted.
+    pub fn total(&self) -> f64 {
+        self.weights.iter().sum()
+    }
+
+    pub fn mean(&self) -> f64 {
+        let total = self.total();
+        if total == 0.0 {
+            return 0.0;
+        }
+        self.weights
+            .iter()
+            .enumerate()
+            .map(|(len, w)| len as f64 * w)
+            .sum::<f64>()
+            / total
+    }
+
+    pub fn sd(&self) -> f64 {
+        let total = self.total();
+        if total == 0.0 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        let var = self
+            .weights
+            .iter()
+            .enumerate()
+            .map(|(len, w)| (len as f64 - mean).powi(2) * w)
+            .sum::<f64>()
+            / total;
+        var.sqrt()
+    }
+
+    /// Number of positions a fragment can start from on each transcript,
+    /// averaged over the fragment lengths that fit in the transcript.
+    /// Transcripts that no fragment fits keep their full length.
+    pub fn effective_lengths(&self, tx_lengths: &[usize]) -> Vec<f64> {
+        // cumulative weight, and weighted length, of fragments up to a length
+        let mut cum_weights = Vec::with_capacity(self.weights.len());
+        let mut cum_lengths = Vec::with_capacity(self.weights.len());
+        let (mut weight_sum, mut length_sum) = (0.0, 0.0);
+        for (len, w) in self.weights.iter().enumerate() {
+            weight_sum += w;
+            length_sum += len as f64 * w;
+            cum_weights.push(weight_sum);
+            cum_lengths.push(length_sum);
+        }
+
+        tx_lengths
+            .iter()
+            .map(|&tx_len| {
+                let max_len = tx_len.min(self.weights.len().saturating_sub(1));
+                if self.weights.is_empty() || cum_weights[max_len] == 0.0 {
+                    return tx_len as f64;
+                }
+                let mean_len = cum_lengths[max_len] / cum_weights[max_len];
+                let eff_len = tx_len as f64 - mean_len + 1.0;
+                if eff_len < 1.0 {
+                    tx_len as f64
+                } else {
+                    eff_len
+                }
+            })
+            .collect()
+    }
+
+    /// Write the counts of the observed lengths to `fragment_lengths.tsv`.
+    pub fn write_tsv<P: AsRef<Path>>(&self, outdir: P) -> Result<(), Error> {
+        let mut outfile = open_file("fragment_lengths.tsv", outdir)?;
+
+        outfile.write_all(FRAGMENT_LENGTHS_HEADER_STRING.as_bytes())?;
+        for (len, &w) in self.weights.iter().enumerate() {
+            if w > 0.0 {
+                writeln!(outfile, "{}\t{}", len, w)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_empirical_dist() {
+        let mut dist = FragmentLengthDist::default();
+        assert_eq!(dist.effective_lengths(&[500]), vec![500.0]);
+
+        for &len in &[100, 200, 300, (MAX_FRAGMENT_LENGTH + 1) as u32] {
+            dist.add(len);
+        }
+        assert_eq!(dist.total(), 3.0);
+        assert_eq!(dist.mean(), 200.0);
+        assert!((dist.sd() - (20000.0f64 / 3.0).sqrt()).abs() < 1e-9);
+
+        // only fragments that fit in the transcript count towards its mean
+        assert_eq!(
+            dist.effective_lengths(&[1000, 250, 50]),
+            vec![801.0, 101.0, 50.0]
+        );
+    }
+
+    #[test]
+    fn test_normal_dist() -> Result<(), Error> {
+        let dist = FragmentLengthDist::normal(200.0, 20.0)?;
+        assert!((dist.mean() - 200.0).abs() < 1e-6);
+        assert!((dist.sd() - 20.0).abs() < 1e-3);
+
+        let eff_lengths = dist.effective_lengths(&[1000]);
+        assert!((eff_lengths[0] - 801.0).abs() < 1e-6);
+
+        assert!(FragmentLengthDist::normal(200.0, 0.0).is_err());
+        assert!(FragmentLengthDist::normal(-1.0, 10.0).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,609 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! On-disk index format: magic bytes and a format version, followed by a
+//! bincode-encoded `IndexHeader` describing the index, the byte length and
+//! bincode encoding of the parts of the `Pseudoaligner` and their checksum,
+//! and finally the kmer node/offset table as raw little-endian `u32` pairs
+//! and its checksum. The table can be memory-mapped and used in place with
+//! `map_index`, so that concurrent mapping processes share it through the
+//! page cache. Only the table is mapped: the graph, equivalence classes and
+//! kmer hash are types of the debruijn and boomphf crates without a layout
+//! that can be used in place, so they are always loaded into memory.
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use bincode::{deserialize_from, serialize_into};
+use boomphf::Mphf;
+use debruijn::dna_string::DnaString;
+use debruijn::graph::DebruijnGraph;
+use debruijn::Kmer;
+use failure::{format_err, Error, ResultExt};
+use memmap::Mmap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::annotation::{GeneInfo, TargetKind};
+use crate::config::IndexConfig;
+use crate::equiv_classes::EqClassIdType;
+use crate::error::MappingError;
+use crate::kmer_index::{KmerIndex, NodeOffsets, NODE_OFFSET_BYTES};
+use crate::pseudoaligner::{NodeTxPosition, Pseudoaligner};
+
+const INDEX_MAGIC: [u8; 8] = *b"DBGMAPIX";
+// bump whenever the layout of the file or of the index parts changes
+pub const INDEX_FORMAT_VERSION: u32 = 8;
+
+// Everything but the kmer node/offset table; keep in sync with `Pseudoaligner`.
+#[derive(Serialize)]
+struct IndexPartsRef<'a, K: Kmer> {
+    index_config: &'a IndexConfig,
+    dbg: &'a DebruijnGraph<K, EqClassIdType>,
+    eq_classes: &'a Vec<Vec<u32>>,
+    kmer_hash: &'a Mphf<K>,
+    tx_names: &'a Vec<String>,
+    tx_lengths: &'a Vec<usize>,
+    tx_gene_mapping: &'a HashMap<String, String>,
+    gene_info: &'a HashMap<String, GeneInfo>,
+    target_kinds: &'a Vec<TargetKind>,
+    node_positions: &'a Option<Vec<Vec<NodeTxPosition>>>,
+}
+
+#[derive(Deserialize)]
+struct IndexParts<K: Kmer> {
+    index_config: IndexConfig,
+    dbg: DebruijnGraph<K, EqClassIdType>,
+    eq_classes: Vec<Vec<u32>>,
+    kmer_hash: Mphf<K>,
+    tx_names: Vec<String>,
+    tx_lengths: Vec<usize>,
+    tx_gene_mapping: HashMap<String, String>,
+    gene_info: HashMap<String, GeneInfo>,
+    target_kinds: Vec<TargetKind>,
+    node_positions: Option<Vec<Vec<NodeTxPosition>>>,
+}
+
+/// Description of an index, readable without loading the index itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexHeader {
+    // version of the crate that built the index
+    pub crate_version: String,
+    // kmer size and the other build parameters
+    pub index_config: IndexConfig,
+    pub num_transcripts: usize,
+    // digest of the reference transcripts, see `transcripts_digest`
+    pub fasta_digest: String,
+}
+
+impl IndexHeader {
+    pub fn new<K: Kmer>(index: &Pseudoaligner<K>, fasta_digest: String) -> IndexHeader {
+        IndexHeader {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            index_config: index.index_config.clone(),
+            num_transcripts: index.tx_names.len(),
+            fasta_digest,
+        }
+    }
+}
+
+/// Digest of the transcript names and sequences an index is built from,
+/// independent of the line wrapping and compression of the FASTA file.
+pub fn transcripts_digest(seqs: &[DnaString], tx_names: &[String]) -> String {
+    let mut hash = Fnv64::new();
+    for (name, seq) in tx_names.iter().zip(seqs) {
+        hash.update(name.as_bytes());
+        hash.update(b"\n");
+        hash.update(seq.to_string().as_bytes());
+        hash.update(b"\n");
+    }
+
+    format!("{:016x}", hash.finish())
+}
+
+/// 64-bit FNV-1a hash, for the transcript digest and the index checksum.
+struct Fnv64(u64);
+
+impl Fnv64 {
+    fn new() -> Fnv64 {
+        Fnv64(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Writer that hashes the bytes written through it.
+struct ChecksumWriter<W> {
+    inner: W,
+    hash: Fnv64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hash.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that hashes the bytes read through it.
+struct ChecksumReader<R> {
+    inner: R,
+    hash: Fnv64,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Write `index` along with its header.
+pub fn write_index<K, P>(
+    index: &Pseudoaligner<K>,
+    header: &IndexHeader,
+    filename: P,
+) -> Result<(), Error>
+where
+    K: Kmer + Serialize,
+    P: AsRef<Path> + Debug,
+{
+    let f = File::create(&filename)
+        .with_context(|_| format!("couldn't create index file {:?}", filename))?;
+    let mut writer = BufWriter::new(f);
+
+    writer.write_all(&INDEX_MAGIC)?;
+    writer.write_all(&INDEX_FORMAT_VERSION.to_le_bytes())?;
+    serialize_into(&mut writer, header)?;
+
+    let parts = IndexPartsRef {
+        index_config: &index.index_config,
+        dbg: &index.dbg,
+        eq_classes: &index.eq_classes,
+        kmer_hash: index.dbg_index.mphf(),
+        tx_names: &index.tx_names,
+        tx_lengths: &index.tx_lengths,
+        tx_gene_mapping: &index.tx_gene_mapping,
+        gene_info: &index.gene_info,
+        target_kinds: &index.target_kinds,
+        node_positions: &index.node_positions,
+    };
+    writer.write_all(&bincode::serialized_size(&parts)?.to_le_bytes())?;
+    let mut parts_writer = ChecksumWriter {
+        inner: &mut writer,
+        hash: Fnv64::new(),
+    };
+    serialize_into(&mut parts_writer, &parts)?;
+    let checksum = parts_writer.hash.finish();
+    writer.write_all(&checksum.to_le_bytes())?;
+
+    let mut table_writer = ChecksumWriter {
+        inner: &mut writer,
+        hash: Fnv64::new(),
+    };
+    let node_offsets = index.dbg_index.node_offsets();
+    table_writer.write_all(&(node_offsets.len() as u64).to_le_bytes())?;
+    for (node_id, offset) in node_offsets.iter() {
+        table_writer.write_all(&node_id.to_le_bytes())?;
+        table_writer.write_all(&offset.to_le_bytes())?;
+    }
+    let checksum = table_writer.hash.finish();
+    writer.write_all(&checksum.to_le_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read and validate the header of an index file.
+pub fn read_index_header<P: AsRef<Path> + Debug>(filename: P) -> Result<IndexHeader, Error> {
+    let mut reader = open_index(&filename)?;
+    read_header(&mut reader, &filename)
+}
+
+/// Check the checksums of the whole index file, without loading the index.
+pub fn verify_index<P: AsRef<Path> + Debug>(filename: P) -> Result<IndexHeader, Error> {
+    let mut reader = open_index(&filename)?;
+    let header = read_header(&mut reader, &filename)?;
+    let corrupt = || format!("index {:?} is truncated or corrupt", filename);
+
+    let parts_len = read_u64(&mut reader).with_context(|_| corrupt())?;
+    let mut parts_reader = ChecksumReader {
+        inner: (&mut reader).take(parts_len),
+        hash: Fnv64::new(),
+    };
+    if io::copy(&mut parts_reader, &mut io::sink())? != parts_len {
+        return Err(format_err!("{}", corrupt()));
+    }
+    let hash = parts_reader.hash.finish();
+    check_checksum(&mut reader, hash, &filename)?;
+
+    let mut table_reader = ChecksumReader {
+        inner: &mut reader,
+        hash: Fnv64::new(),
+    };
+    let num_kmers = read_u64(&mut table_reader).with_context(|_| corrupt())?;
+    let table_len = num_kmers * NODE_OFFSET_BYTES as u64;
+    if io::copy(&mut (&mut table_reader).take(table_len), &mut io::sink())? != table_len {
+        return Err(format_err!("{}", corrupt()));
+    }
+    let hash = table_reader.hash.finish();
+    check_checksum(&mut reader, hash, &filename)?;
+    Ok(header)
+}
+
+/// Load an index into memory, checking that it is built with kmer type `K`.
+pub fn read_index<K, P>(filename: P) -> Result<(IndexHeader, Pseudoaligner<K>), Error>
+where
+    K: Kmer + Sync + Send + DeserializeOwned,
+    P: AsRef<Path> + Debug,
+{
+    load_index(filename, false)
+}
+
+/// Load an index, using its kmer node/offset table in place from a
+/// memory-mapped file rather than reading it into memory. Only the table is
+/// mapped: the graph, equivalence classes and kmer hash are deserialized as
+/// with `read_index`. The checksum of the table isn't checked, as that would
+/// read all of it; use `verify_index` for that. The index file must not be
+/// modified while the returned index is in use.
+pub fn map_index<K, P>(filename: P) -> Result<(IndexHeader, Pseudoaligner<K>), Error>
+where
+    K: Kmer + Sync + Send + DeserializeOwned,
+    P: AsRef<Path> + Debug,
+{
+    load_index(filename, true)
+}
+
+fn load_index<K, P>(filename: P, mmap: bool) -> Result<(IndexHeader, Pseudoaligner<K>), Error>
+where
+    K: Kmer + Sync + Send + DeserializeOwned,
+    P: AsRef<Path> + Debug,
+{
+    let mut reader = open_index(&filename)?;
+    let header = read_header(&mut reader, &filename)?;
+
+    if header.index_config.kmer_size != K::k() {
+        return Err(MappingError::IncompatibleIndex(format!(
+            "index {:?} has kmer size {}, but was loaded with kmer size {}",
+            filename,
+            header.index_config.kmer_size,
+            K::k()
+        ))
+        .into());
+    }
+
+    let corrupt = || format!("index {:?} is truncated or corrupt", filename);
+    let parts_len = read_u64(&mut reader).with_context(|_| corrupt())?;
+    let mut parts_reader = ChecksumReader {
+        inner: (&mut reader).take(parts_len),
+        hash: Fnv64::new(),
+    };
+    let parts: IndexParts<K> = deserialize_from(&mut parts_reader).with_context(|_| corrupt())?;
+    let hash = parts_reader.hash.finish();
+    check_checksum(&mut reader, hash, &filename)?;
+    if parts.index_config != header.index_config {
+        return Err(format_err!(
+            "index {:?} is corrupt: header and index settings differ",
+            filename
+        ));
+    }
+
+    let mut table_reader = ChecksumReader {
+        inner: &mut reader,
+        hash: Fnv64::new(),
+    };
+    let num_kmers = read_u64(&mut table_reader).with_context(|_| corrupt())? as usize;
+
+    let node_offsets = if mmap {
+        let start = reader.seek(SeekFrom::Current(0))? as usize;
+        // Safety: the index file is treated as read-only while mapped
+        let mmap = Arc::new(unsafe { Mmap::map(reader.get_ref())? });
+        let end = start + num_kmers * NODE_OFFSET_BYTES;
+        if mmap.len() < end + 8 {
+            return Err(format_err!("{}", corrupt()));
+        }
+        NodeOffsets::mapped(mmap, start, num_kmers).with_context(|_| corrupt())?
+    } else {
+        let mut node_offsets = Vec::with_capacity(num_kmers);
+        let mut entry = [0u8; NODE_OFFSET_BYTES];
+        for _ in 0..num_kmers {
+            table_reader
+                .read_exact(&mut entry)
+                .with_context(|_| corrupt())?;
+            let mut node_id = [0u8; 4];
+            let mut offset = [0u8; 4];
+            node_id.copy_from_slice(&entry[..4]);
+            offset.copy_from_slice(&entry[4..]);
+            node_offsets.push((u32::from_le_bytes(node_id), u32::from_le_bytes(offset)));
+        }
+        let hash = table_reader.hash.finish();
+        check_checksum(&mut reader, hash, &filename)?;
+        NodeOffsets::Owned(node_offsets)
+    };
+
+    let mut index = Pseudoaligner::new(
+        parts.dbg,
+        parts.eq_classes,
+        KmerIndex::from_parts(parts.kmer_hash, node_offsets),
+        parts.tx_names,
+        parts.tx_lengths,
+        parts.tx_gene_mapping,
+        parts.index_config,
+    );
+    if parts.target_kinds.len() != index.tx_names.len() {
+        return Err(format_err!(
+            "index {:?} is corrupt: {} target kinds for {} targets",
+            filename,
+            parts.target_kinds.len(),
+            index.tx_names.len()
+        ));
+    }
+    if let Some(node_positions) = &parts.node_positions {
+        if node_positions.len() != index.dbg.len() {
+            return Err(format_err!(
+                "index {:?} is corrupt: node positions for {} nodes, graph has {}",
+                filename,
+                node_positions.len(),
+                index.dbg.len()
+            ));
+        }
+    }
+    index.gene_info = parts.gene_info;
+    index.target_kinds = parts.target_kinds;
+    index.node_positions = parts.node_positions;
+    Ok((header, index))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Compare the checksum stored next in `reader` with `hash`, the checksum of
+/// the bytes before it.
+fn check_checksum<R: Read, P: Debug>(reader: &mut R, hash: u64, filename: P) -> Result<(), Error> {
+    let checksum = read_u64(reader)
+        .with_context(|_| format!("index {:?} is truncated or corrupt", filename))?;
+    if checksum != hash {
+        return Err(format_err!(
+            "index {:?} is corrupt: checksum mismatch",
+            filename
+        ));
+    }
+    Ok(())
+}
+
+fn open_index<P: AsRef<Path> + Debug>(filename: P) -> Result<BufReader<File>, Error> {
+    let f = File::open(&filename).map_err(|err| MappingError::io(&filename, err))?;
+    Ok(BufReader::new(f))
+}
+
+fn read_header<R: Read, P: Debug>(reader: &mut R, filename: P) -> Result<IndexHeader, Error> {
+    let mut magic = [0u8; 8];
+    let mut version = [0u8; 4];
+    if reader.read_exact(&mut magic).is_err() || magic != INDEX_MAGIC {
+        return Err(MappingError::IncompatibleIndex(format!(
+            "{:?} is not a pseudoaligner index, or was built by a version older than {}",
+            filename,
+            env!("CARGO_PKG_VERSION")
+        ))
+        .into());
+    }
+
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != INDEX_FORMAT_VERSION {
+        return Err(MappingError::IncompatibleIndex(format!(
+            "index {:?} has format version {}, but this build reads version {}; rebuild the index",
+            filename, version, INDEX_FORMAT_VERSION
+        ))
+        .into());
+    }
+
+    let header = deserialize_from(reader)
+        .with_context(|_| format!("index {:?} has a corrupt header", filename))?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::annotation::Annotation;
+    use crate::build_index::build_index;
+    use crate::config;
+    use crate::test_data::{first_transcript, small_index_with, small_transcripts, test_dir};
+    use debruijn::kmer::{Kmer16, Kmer24, Kmer31};
+
+    #[test]
+    fn test_index_file_roundtrip() -> Result<(), Error> {
+        let (seqs, tx_names, _) = small_transcripts();
+        let mut index = small_index_with(&IndexConfig::default());
+        index.gene_info.insert(
+            "ENSG00000223972.5".to_string(),
+            GeneInfo {
+                gene_name: Some("DDX11L1".to_string()),
+                gene_biotype: None,
+            },
+        );
+        let digest = transcripts_digest(seqs, tx_names);
+        let header = IndexHeader::new(&index, digest.clone());
+
+        let dir = test_dir("index_file_roundtrip");
+        let path = dir.join("index.bin");
+        write_index(&index, &header, &path)?;
+
+        assert_eq!(read_index_header(&path)?, header);
+        assert_eq!(verify_index(&path)?, header);
+        let (loaded_header, loaded_index) = read_index::<config::KmerType, _>(&path)?;
+        assert_eq!(loaded_header.fasta_digest, digest);
+        assert_eq!(loaded_header.num_transcripts, tx_names.len());
+        assert_eq!(loaded_index.tx_names, index.tx_names);
+        assert_eq!(loaded_index.gene_info, index.gene_info);
+        assert_eq!(loaded_index.gene_name("ENSG00000223972.5"), "DDX11L1");
+        assert!(!loaded_index.dbg_index.is_mapped());
+
+        let (_, mapped_index) = map_index::<config::KmerType, _>(&path)?;
+        assert!(mapped_index.dbg_index.is_mapped());
+        assert_eq!(mapped_index.dbg_index.len(), index.dbg_index.len());
+        let node_offsets = index.dbg_index.node_offsets();
+        assert!(node_offsets
+            .iter()
+            .eq(mapped_index.dbg_index.node_offsets().iter()));
+
+        // the mapped index maps reads like the in-memory one
+        let read = &seqs[0];
+        let mapping_config = config::MappingConfig::default();
+        assert_eq!(
+            mapped_index.map_read(read, &mapping_config),
+            index.map_read(read, &mapping_config)
+        );
+
+        // truncated file
+        let bytes = std::fs::read(&path)?;
+        let truncated_path = dir.join("truncated.bin");
+        std::fs::write(&truncated_path, &bytes[..bytes.len() - 4])?;
+        assert!(map_index::<config::KmerType, _>(&truncated_path).is_err());
+        assert!(read_index::<config::KmerType, _>(&truncated_path).is_err());
+        assert!(verify_index(&truncated_path).is_err());
+
+        // flipped bit in the kmer table, only caught when the table is read
+        let mut bytes = std::fs::read(&path)?;
+        let last_entry = bytes.len() - 8 - NODE_OFFSET_BYTES;
+        bytes[last_entry] ^= 1;
+        let corrupt_path = dir.join("corrupt.bin");
+        std::fs::write(&corrupt_path, &bytes)?;
+        assert!(map_index::<config::KmerType, _>(&corrupt_path).is_ok());
+        assert!(read_index::<config::KmerType, _>(&corrupt_path).is_err());
+        assert!(verify_index(&corrupt_path).is_err());
+
+        // flipped bit in a transcript name in the index parts
+        let mut bytes = std::fs::read(&path)?;
+        let header_len = bincode::serialized_size(&header)? as usize;
+        let parts_start = INDEX_MAGIC.len() + 4 + header_len + 8;
+        let last_name = tx_names.last().unwrap().as_bytes();
+        let name_pos = bytes[parts_start..]
+            .windows(last_name.len())
+            .position(|w| w == last_name)
+            .unwrap();
+        bytes[parts_start + name_pos] ^= 1;
+        std::fs::write(&corrupt_path, &bytes)?;
+        assert!(map_index::<config::KmerType, _>(&corrupt_path).is_err());
+        assert!(read_index::<config::KmerType, _>(&corrupt_path).is_err());
+        assert!(verify_index(&corrupt_path).is_err());
+
+        // wrong kmer size
+        assert!(read_index::<Kmer24, _>(&path).is_err());
+
+        // not an index
+        let bad_path = dir.join("not_an_index.bin");
+        std::fs::write(&bad_path, b"garbage")?;
+        assert!(read_index_header(&bad_path).is_err());
+
+        // unknown format version
+        let mut bytes = std::fs::read(&path)?;
+        bytes[INDEX_MAGIC.len()] = 0xff;
+        std::fs::write(&bad_path, &bytes)?;
+        assert!(read_index_header(&bad_path).is_err());
+        Ok(())
+    }
+
+    /// Build an index of the small transcripts with kmer type `K`, write it,
+    /// and check that it maps a read alike once read or mapped back.
+    fn check_kmer_size_roundtrip<K>(name: &str) -> Result<(), Error>
+    where
+        K: Kmer + Sync + Send + Serialize + DeserializeOwned,
+    {
+        let (seqs, tx_names, tx_gene_map) = small_transcripts();
+        let index_config = IndexConfig {
+            kmer_size: K::k(),
+            ..IndexConfig::default()
+        };
+        let index: Pseudoaligner<K> = build_index(seqs, tx_names, tx_gene_map, &index_config, 2)?;
+        let header = IndexHeader::new(&index, transcripts_digest(seqs, tx_names));
+        let path = test_dir(name).join("index.bin");
+        write_index(&index, &header, &path)?;
+        assert_eq!(read_index_header(&path)?.index_config.kmer_size, K::k());
+
+        let read = DnaString::from_acgt_bytes(&first_transcript()[100..200]);
+        let mapping_config = config::MappingConfig::default();
+        let (eq_class, coverage) = index.map_read(&read, &mapping_config).unwrap();
+        assert!(eq_class.contains(&0));
+        assert_eq!(coverage, 100);
+
+        let (_, loaded_index) = read_index::<K, _>(&path)?;
+        let (_, mapped_index) = map_index::<K, _>(&path)?;
+        for other in &[loaded_index, mapped_index] {
+            assert_eq!(
+                other.map_read(&read, &mapping_config),
+                Some((eq_class.clone(), coverage))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_kmer_size_roundtrip() -> Result<(), Error> {
+        check_kmer_size_roundtrip::<Kmer31>("kmer31_roundtrip")?;
+        check_kmer_size_roundtrip::<Kmer16>("kmer16_roundtrip")
+    }
+
+    #[test]
+    fn test_annotated_index_roundtrip() -> Result<(), Error> {
+        // a GTF of the FASTA transcripts, in another order and with a
+        // transcript the FASTA doesn't have
+        let (seqs, tx_names, tx_gene_map) = small_transcripts();
+        let dir = test_dir("annotated_index_roundtrip");
+        let gtf = dir.join("annotation.gtf");
+        let mut records = String::new();
+        for tx_name in tx_names.iter().rev() {
+            records.push_str(&format!(
+                "chr1\ttest\texon\t1\t100\t.\t+\t.\tgene_id \"{}\"; transcript_id \"{}\";\n",
+                tx_gene_map[tx_name], tx_name
+            ));
+        }
+        records
+            .push_str("chr2\ttest\texon\t1\t100\t.\t+\t.\tgene_id \"g\"; transcript_id \"t\";\n");
+        std::fs::write(&gtf, records)?;
+        let annotation = Annotation::read(&gtf)?;
+
+        let mut index = small_index_with(&IndexConfig::default());
+        index.gene_info = annotation.genes.clone();
+        index.target_kinds = annotation.target_kinds(&index.tx_names);
+        let header = IndexHeader::new(&index, transcripts_digest(seqs, tx_names));
+        let path = dir.join("index.bin");
+        write_index(&index, &header, &path)?;
+
+        let (_, loaded_index) = read_index::<config::KmerType, _>(&path)?;
+        assert_eq!(loaded_index.target_kinds.len(), tx_names.len());
+        assert_eq!(loaded_index.target_kinds, index.target_kinds);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcripts_digest() {
+        let seqs = vec![DnaString::from_dna_string("ACGT")];
+        let names = vec!["tx1".to_string()];
+        let digest = transcripts_digest(&seqs, &names);
+        assert_eq!(digest.len(), 16);
+        assert_eq!(digest, transcripts_digest(&seqs, &names));
+
+        let other_seqs = vec![DnaString::from_dna_string("ACGA")];
+        assert_ne!(digest, transcripts_digest(&other_seqs, &names));
+    }
+}
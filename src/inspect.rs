@@ -0,0 +1,99 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Report how the index and a set of mapped reads cover individual genes.
+use std::io::Write;
+
+use debruijn::Kmer;
+use failure::Error;
+use itertools::Itertools;
+use log::warn;
+
+use crate::error::MappingError;
+use crate::pseudoaligner::Pseudoaligner;
+use crate::quant::EqClassCounts;
+
+/// For each gene in `genes`, write its transcripts, the graph nodes whose
+/// equivalence class includes any of those transcripts, and the read counts
+/// of every observed equivalence class that touches the gene.
+pub fn inspect_genes<K: Kmer, W: Write>(
+    index: &Pseudoaligner<K>,
+    eq_counts: &EqClassCounts,
+    genes: &[String],
+    mut out: W,
+) -> Result<(), Error> {
+    for eq_class in eq_counts.keys() {
+        if let Some(t) = eq_class
+            .iter()
+            .find(|&&t| t as usize >= index.tx_names.len())
+        {
+            return Err(MappingError::Parse(format!(
+                "equivalence class counts refer to transcript {}, but the index has {}",
+                t,
+                index.tx_names.len()
+            ))
+            .into());
+        }
+    }
+
+    let tx_list = |eq_class: &[u32]| {
+        eq_class
+            .iter()
+            .map(|&t| &index.tx_names[t as usize])
+            .join(",")
+    };
+
+    for gene in genes {
+        let transcripts: Vec<(u32, &String, usize)> = index
+            .tx_names
+            .iter()
+            .zip(&index.tx_lengths)
+            .enumerate()
+            .filter(|(_, (tx_name, _))| index.tx_gene_mapping.get(*tx_name) == Some(gene))
+            .map(|(t, (tx_name, &tx_len))| (t as u32, tx_name, tx_len))
+            .collect();
+
+        if transcripts.is_empty() {
+            warn!("Gene {} not found in the index", gene);
+            continue;
+        }
+
+        writeln!(out, "gene\t{}\t{} transcripts", gene, transcripts.len())?;
+        for (t, tx_name, tx_len) in &transcripts {
+            writeln!(out, "transcript\t{}\t{}\t{}", t, tx_name, tx_len)?;
+        }
+        let tx_ids: Vec<u32> = transcripts.iter().map(|&(t, _, _)| t).collect();
+
+        for node in index.dbg.iter_nodes() {
+            let eq_class_id = *node.data();
+            let eq_class = &index.eq_classes[eq_class_id as usize];
+
+            if touches(eq_class, &tx_ids) {
+                writeln!(
+                    out,
+                    "node\t{}\t{}\t{}\t{}",
+                    node.node_id,
+                    node.len(),
+                    eq_class_id,
+                    tx_list(eq_class)
+                )?;
+            }
+        }
+
+        let mut read_classes: Vec<_> = eq_counts
+            .iter()
+            .filter(|(eq_class, _)| touches(eq_class, &tx_ids))
+            .collect();
+        read_classes.sort();
+
+        for (eq_class, count) in read_classes {
+            writeln!(out, "reads\t{}\t{}", count, tx_list(eq_class))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check whether a sorted equivalence class shares a transcript with the sorted `tx_ids`.
+fn touches(eq_class: &[u32], tx_ids: &[u32]) -> bool {
+    eq_class.iter().any(|t| tx_ids.binary_search(t).is_ok())
+}
@@ -0,0 +1,155 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Kmer lookup table of the pseudoaligner: a minimal perfect hash over the
+//! kmers of the graph, and for each kmer the graph node and offset in the node
+//! where it occurs. The node/offset table holds one entry per kmer, which makes
+//! it the largest array of an index. It can therefore be used in place from a
+//! memory-mapped index file, see `index_file::map_index`.
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use boomphf::Mphf;
+use failure::{format_err, Error};
+use memmap::Mmap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// bytes of one node/offset entry in a mapped table: two little-endian u32s
+pub(crate) const NODE_OFFSET_BYTES: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KmerIndex<K: Hash> {
+    mphf: Mphf<K>,
+    node_offsets: NodeOffsets,
+}
+
+impl<K: Hash + fmt::Debug> KmerIndex<K> {
+    pub fn new(mphf: Mphf<K>, node_offsets: Vec<(u32, u32)>) -> KmerIndex<K> {
+        KmerIndex {
+            mphf,
+            node_offsets: NodeOffsets::Owned(node_offsets),
+        }
+    }
+
+    pub(crate) fn from_parts(mphf: Mphf<K>, node_offsets: NodeOffsets) -> KmerIndex<K> {
+        KmerIndex { mphf, node_offsets }
+    }
+
+    /// Node id and offset of `kmer` in the graph. Kmers that are not in the
+    /// graph may still return a position, so callers have to verify the hit.
+    pub fn get(&self, kmer: &K) -> Option<(u32, u32)> {
+        self.mphf
+            .try_hash(kmer)
+            .map(|pos| self.node_offsets.get(pos as usize))
+    }
+
+    /// Number of kmers in the index.
+    pub fn len(&self) -> usize {
+        self.node_offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn mphf(&self) -> &Mphf<K> {
+        &self.mphf
+    }
+
+    pub(crate) fn node_offsets(&self) -> &NodeOffsets {
+        &self.node_offsets
+    }
+
+    /// Whether the node/offset table is read from a memory-mapped file.
+    pub fn is_mapped(&self) -> bool {
+        match self.node_offsets {
+            NodeOffsets::Owned(_) => false,
+            NodeOffsets::Mapped { .. } => true,
+        }
+    }
+}
+
+/// Node/offset table, held in memory or read in place from a mapped file.
+pub enum NodeOffsets {
+    Owned(Vec<(u32, u32)>),
+    Mapped {
+        mmap: Arc<Mmap>,
+        // start of the table in `mmap`, in bytes
+        start: usize,
+        len: usize,
+    },
+}
+
+impl NodeOffsets {
+    /// Table of `len` entries starting `start` bytes into `mmap`.
+    pub(crate) fn mapped(mmap: Arc<Mmap>, start: usize, len: usize) -> Result<NodeOffsets, Error> {
+        let end = len
+            .checked_mul(NODE_OFFSET_BYTES)
+            .and_then(|bytes| bytes.checked_add(start));
+        match end {
+            Some(end) if end <= mmap.len() => Ok(NodeOffsets::Mapped { mmap, start, len }),
+            _ => Err(format_err!(
+                "kmer table of {} entries at byte {} exceeds the mapped file size {}",
+                len,
+                start,
+                mmap.len()
+            )),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            NodeOffsets::Owned(v) => v.len(),
+            NodeOffsets::Mapped { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> (u32, u32) {
+        match self {
+            NodeOffsets::Owned(v) => v[i],
+            NodeOffsets::Mapped { mmap, start, len } => {
+                assert!(i < *len, "kmer table index {} out of bounds", i);
+                let pos = start + i * NODE_OFFSET_BYTES;
+                let bytes = &mmap[pos..pos + NODE_OFFSET_BYTES];
+                let mut node_id = [0u8; 4];
+                let mut offset = [0u8; 4];
+                node_id.copy_from_slice(&bytes[..4]);
+                offset.copy_from_slice(&bytes[4..]);
+                (u32::from_le_bytes(node_id), u32::from_le_bytes(offset))
+            }
+        }
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (u32, u32)> + 'a {
+        (0..self.len()).map(move |i| self.get(i))
+    }
+}
+
+impl fmt::Debug for NodeOffsets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeOffsets::Owned(v) => write!(f, "NodeOffsets::Owned({} entries)", v.len()),
+            NodeOffsets::Mapped { start, len, .. } => {
+                write!(f, "NodeOffsets::Mapped({} entries at byte {})", len, start)
+            }
+        }
+    }
+}
+
+// A mapped table is written out like an owned one, and always read back
+// into memory.
+impl Serialize for NodeOffsets {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeOffsets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<NodeOffsets, D::Error> {
+        Vec::deserialize(deserializer).map(NodeOffsets::Owned)
+    }
+}
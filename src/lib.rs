@@ -1,10 +1,24 @@
 // Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
 
+pub mod annotation;
+pub mod bootstrap;
 pub mod build_index;
 pub mod config;
 
 pub mod equiv_classes;
+pub mod error;
+pub mod fragment_length;
+pub mod index_file;
+pub mod inspect;
+pub mod kmer_index;
 pub mod mappability;
+pub mod output;
 pub mod pseudoaligner;
+pub mod quant;
+pub mod report;
 pub mod scatter;
+pub mod single_cell;
+#[cfg(test)]
+mod test_data;
 pub mod utils;
+pub mod velocity;
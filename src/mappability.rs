@@ -7,11 +7,12 @@ use std::io::Write;
 use std::path::Path;
 
 use crate::config::MAPPABILITY_COUNTS_LEN;
+use crate::error::MappingError;
 use crate::pseudoaligner::Pseudoaligner;
 use crate::utils::open_file;
 
 // 1. Given graph, build a data structure of transcripts
-//    - tx: tx_name, gene_name,
+//    - tx: tx_name, gene_id, gene_name,
 // 2. For each de Bruijn graph node
 //    - count = number of kmers (L - K + 1)
 //    - transcript multiplicity = # of colors (size of equiv class)
@@ -20,29 +21,31 @@ use crate::utils::open_file;
 //    - add count, gene multiplicity to gene_mappability
 // 3. Output results to tx_mappability.tsv and gene_mappability.tsv
 //    - tx_mappability:
-//      tx_name gene_name length kmer_count fraction_unique_tx fraction_unique_gene
-// MappabilityRecord: tx_name, gene_name, tx_multiplicity: [usize], gene_multiplicity: [usize]
+//      tx_name gene_id gene_name length kmer_count fraction_unique_tx fraction_unique_gene
+// MappabilityRecord: tx_name, gene_id, gene_name, tx_multiplicity: [usize], gene_multiplicity: [usize]
 //
 // fn update_counts(Vec<Record>, kmer_count, ids)
 //    fn update_counts(self, kmer_count, ids), Option(Gene_tx_map))
 //      - (if gene we'll need to make a gene vector instead of color)
 //    fn fraction_unique(self) -> f64
 const MAPPABILITY_HEADER_STRING: &'static str =
-    "tx_name\tgene_name\ttx_kmer_count\tfrac_kmer_unique_tx\tfrac_kmer_unique_gene\n";
+    "tx_name\tgene_id\tgene_name\ttx_kmer_count\tfrac_kmer_unique_tx\tfrac_kmer_unique_gene\n";
 
 #[derive(Debug)]
 pub struct MappabilityRecord {
     pub tx_name: String,
+    pub gene_id: String,
     pub gene_name: String,
     tx_multiplicity: [usize; MAPPABILITY_COUNTS_LEN],
     gene_multiplicity: [usize; MAPPABILITY_COUNTS_LEN],
 }
 
 impl MappabilityRecord {
-    pub fn new(tx_name: &String, gene_name: &String) -> MappabilityRecord {
+    pub fn new(tx_name: &str, gene_id: &str, gene_name: &str) -> MappabilityRecord {
         MappabilityRecord {
-            tx_name: tx_name.clone(),
-            gene_name: gene_name.clone(),
+            tx_name: tx_name.to_string(),
+            gene_id: gene_id.to_string(),
+            gene_name: gene_name.to_string(),
             // tx_multiplicity[j] = # of kmers in this tx shared by j other transcripts
             tx_multiplicity: [0; MAPPABILITY_COUNTS_LEN],
             // gene_multiplicity[j] = # of kmers in the tx shared by j other genes
@@ -80,8 +83,9 @@ impl MappabilityRecord {
 
     pub fn to_tsv(&self) -> String {
         format!(
-            "{}\t{}\t{}\t{}\t{}",
+            "{}\t{}\t{}\t{}\t{}\t{}",
             self.tx_name,
+            self.gene_id,
             self.gene_name,
             self.total_kmer_count(),
             self.fraction_unique_tx(),
@@ -117,13 +121,21 @@ pub fn write_mappability_tsv<P: AsRef<Path>>(
 //     }
 // }
 
-pub fn analyze_graph<K: Kmer>(index: &Pseudoaligner<K>) -> Result<(Vec<MappabilityRecord>), Error> {
+pub fn analyze_graph<K: Kmer + Sync + Send>(
+    index: &Pseudoaligner<K>,
+) -> Result<(Vec<MappabilityRecord>), Error> {
     let mut records = Vec::new();
 
     // Make records
     for tx_name in index.tx_names.iter() {
-        let gene_name = index.tx_gene_mapping.get(tx_name).unwrap();
-        records.push(MappabilityRecord::new(&tx_name, &gene_name));
+        let gene_id = index.tx_gene_mapping.get(tx_name).ok_or_else(|| {
+            MappingError::Parse(format!("transcript {} has no gene in the index", tx_name))
+        })?;
+        records.push(MappabilityRecord::new(
+            tx_name,
+            gene_id,
+            index.gene_name(gene_id),
+        ));
     }
 
     // Iterate through graph
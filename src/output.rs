@@ -0,0 +1,507 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Writers for per-read pseudoalignment results.
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bincode::{self, deserialize_from, serialize_into};
+use failure::{format_err, Error};
+use itertools::Itertools;
+
+use crate::error::MappingError;
+use crate::pseudoaligner::{ReadMapping, Strand, TxPosition};
+use crate::utils::open_file;
+
+const READS_HEADER_STRING: &'static str = "read_id\tmapped\tcoverage\tstrand\ttranscripts";
+
+/// File format of the per-read output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadOutputFormat {
+    // TSV listing the names of the compatible transcripts
+    Tsv,
+    // TSV listing the ids of the compatible transcripts
+    TsvIds,
+    // bincode-encoded `ReadMapping` records
+    Bin,
+    // SAM records against the transcripts
+    Sam,
+}
+
+impl FromStr for ReadOutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ReadOutputFormat, Error> {
+        match s {
+            "tsv" => Ok(ReadOutputFormat::Tsv),
+            "tsv-ids" => Ok(ReadOutputFormat::TsvIds),
+            "bin" => Ok(ReadOutputFormat::Bin),
+            "sam" => Ok(ReadOutputFormat::Sam),
+            _ => Err(format_err!("unknown read output format: {}", s)),
+        }
+    }
+}
+
+/// Destination for the results of a mapping run, one read at a time.
+pub trait ReadWriter {
+    fn write_read(&mut self, read: &ReadMapping) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error>;
+}
+
+/// Tab-separated output with one line per read: read name, mapped flag (0/1),
+/// bases covered, strand of the hit (+, - or . if unaligned), and
+/// comma-separated compatible transcripts. With `positions`, a last column
+/// lists the positions of the read, as comma-separated
+/// `<transcript>:<start>:<strand>` with the 0-based start on the transcript.
+pub struct TsvReadWriter<'a, W: Write> {
+    writer: W,
+    // transcript names to report, or None to report transcript ids
+    tx_names: Option<&'a [String]>,
+    positions: bool,
+}
+
+impl<'a, W: Write> TsvReadWriter<'a, W> {
+    pub fn new(
+        mut writer: W,
+        tx_names: Option<&'a [String]>,
+        positions: bool,
+    ) -> Result<TsvReadWriter<'a, W>, Error> {
+        writer.write_all(READS_HEADER_STRING.as_bytes())?;
+        if positions {
+            writer.write_all(b"\tpositions")?;
+        }
+        writeln!(writer)?;
+        Ok(TsvReadWriter {
+            writer,
+            tx_names,
+            positions,
+        })
+    }
+
+    fn tx_name(&self, t: u32) -> String {
+        match self.tx_names {
+            Some(tx_names) => tx_names[t as usize].clone(),
+            None => t.to_string(),
+        }
+    }
+}
+
+impl<'a, W: Write> ReadWriter for TsvReadWriter<'a, W> {
+    fn write_read(&mut self, read: &ReadMapping) -> Result<(), Error> {
+        let transcripts = read.eq_class.iter().map(|&t| self.tx_name(t)).join(",");
+        let strand = read.strand.map_or('.', |strand| strand.symbol());
+
+        write!(
+            self.writer,
+            "{}\t{}\t{}\t{}\t{}",
+            read.read_id, read.mapped as u8, read.coverage, strand, transcripts
+        )?;
+        if self.positions {
+            let positions = read
+                .positions
+                .iter()
+                .map(|p| format!("{}:{}:{}", self.tx_name(p.tx_id), p.pos, p.strand.symbol()))
+                .join(",");
+            write!(self.writer, "\t{}", positions)?;
+        }
+        writeln!(self.writer)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// SAM output with one record per compatible transcript that a read has a
+/// position on, see `ReadMapping::positions`: the first is the primary
+/// alignment, the others are secondary (flag 0x100). Parts of the read
+/// hanging off the transcript are soft clipped. A mapped read without a
+/// position on any of its transcripts gets a record per transcript with
+/// POS 0 and CIGAR `*`; reads that didn't map get a single unmapped record
+/// (flag 0x4). Needs an index built with positions. A read pair is
+/// reported as one unpaired record of the first mate. The sequence and
+/// qualities are not kept, so they are always `*`. Tags: `NH` number of
+/// compatible transcripts, `ZE` equivalence class id, in order of first
+/// appearance in the file, `ZC` bases covered and `ZF` fragment length of a
+/// pair.
+pub struct SamReadWriter<'a, W: Write> {
+    writer: W,
+    tx_names: &'a [String],
+    tx_lengths: &'a [usize],
+    eq_class_ids: HashMap<Vec<u32>, usize>,
+}
+
+impl<'a, W: Write> SamReadWriter<'a, W> {
+    pub fn new(
+        mut writer: W,
+        tx_names: &'a [String],
+        tx_lengths: &'a [usize],
+    ) -> Result<SamReadWriter<'a, W>, Error> {
+        writeln!(writer, "@HD\tVN:1.6\tSO:unsorted")?;
+        for (tx_name, tx_length) in tx_names.iter().zip(tx_lengths) {
+            writeln!(writer, "@SQ\tSN:{}\tLN:{}", tx_name, tx_length)?;
+        }
+        writeln!(
+            writer,
+            "@PG\tID:pseudoaligner\tPN:pseudoaligner\tVN:{}",
+            env!("CARGO_PKG_VERSION")
+        )?;
+
+        Ok(SamReadWriter {
+            writer,
+            tx_names,
+            tx_lengths,
+            eq_class_ids: HashMap::new(),
+        })
+    }
+}
+
+impl<'a, W: Write> ReadWriter for SamReadWriter<'a, W> {
+    fn write_read(&mut self, read: &ReadMapping) -> Result<(), Error> {
+        // QNAME can't contain whitespace
+        let read_id = read.read_id.split_whitespace().next().unwrap_or("*");
+
+        if !read.mapped || read.eq_class.is_empty() {
+            writeln!(
+                self.writer,
+                "{}\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\tZC:i:{}",
+                read_id, read.coverage
+            )?;
+            return Ok(());
+        }
+
+        let next_id = self.eq_class_ids.len();
+        let eq_class_id = *self
+            .eq_class_ids
+            .entry(read.eq_class.clone())
+            .or_insert(next_id);
+        let num_hits = read.eq_class.len();
+        let mapq = if num_hits == 1 { 255 } else { 0 };
+
+        let hits: Vec<(u32, &TxPosition, i32, String)> = read
+            .eq_class
+            .iter()
+            .filter_map(|&t| {
+                let position = read.positions.iter().find(|p| p.tx_id == t)?;
+                let (pos, cigar) = sam_position(position, self.tx_lengths[t as usize])?;
+                Some((t, position, pos, cigar))
+            })
+            .collect();
+
+        if hits.is_empty() {
+            let strand_flag = match read.strand {
+                Some(Strand::Reverse) => 0x10,
+                _ => 0,
+            };
+            for (i, &t) in read.eq_class.iter().enumerate() {
+                let flag = if i > 0 {
+                    strand_flag | 0x100
+                } else {
+                    strand_flag
+                };
+                writeln!(
+                    self.writer,
+                    "{}\t{}\t{}\t0\t{}\t*\t*\t0\t0\t*\t*\tNH:i:{}\tZE:i:{}\tZC:i:{}",
+                    read_id,
+                    flag,
+                    self.tx_names[t as usize],
+                    mapq,
+                    num_hits,
+                    eq_class_id,
+                    read.coverage
+                )?;
+            }
+            return Ok(());
+        }
+
+        for (i, (t, position, pos, cigar)) in hits.iter().enumerate() {
+            let mut flag = match position.strand {
+                Strand::Reverse => 0x10,
+                Strand::Forward => 0,
+            };
+            if i > 0 {
+                flag |= 0x100;
+            }
+
+            write!(
+                self.writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t*\t*\tNH:i:{}\tZE:i:{}\tZC:i:{}",
+                read_id,
+                flag,
+                self.tx_names[*t as usize],
+                pos,
+                mapq,
+                cigar,
+                num_hits,
+                eq_class_id,
+                read.coverage
+            )?;
+            if let Some(fragment_len) = position.fragment_len {
+                write!(self.writer, "\tZF:i:{}", fragment_len)?;
+            }
+            writeln!(self.writer)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// 1-based SAM position and CIGAR of a read on a transcript of `tx_length`
+/// bases, soft clipping the bases outside the transcript. `None` if the read
+/// doesn't overlap the transcript.
+fn sam_position(position: &TxPosition, tx_length: usize) -> Option<(i32, String)> {
+    let start = position.pos;
+    let end = position.pos + position.read_len as i32;
+    let left_clip = (-start).max(0);
+    let right_clip = (end - tx_length as i32).max(0);
+    let matched = position.read_len as i32 - left_clip - right_clip;
+    if matched <= 0 {
+        return None;
+    }
+
+    let mut cigar = String::new();
+    if left_clip > 0 {
+        cigar.push_str(&format!("{}S", left_clip));
+    }
+    cigar.push_str(&format!("{}M", matched));
+    if right_clip > 0 {
+        cigar.push_str(&format!("{}S", right_clip));
+    }
+    Some((start.max(0) + 1, cigar))
+}
+
+/// Binary output: a stream of bincode-encoded `ReadMapping` records.
+pub struct BinReadWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> BinReadWriter<W> {
+    pub fn new(writer: W) -> BinReadWriter<W> {
+        BinReadWriter { writer }
+    }
+}
+
+impl<W: Write> ReadWriter for BinReadWriter<W> {
+    fn write_read(&mut self, read: &ReadMapping) -> Result<(), Error> {
+        serialize_into(&mut self.writer, read)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Collect the results in memory.
+impl ReadWriter for Vec<ReadMapping> {
+    fn write_read(&mut self, read: &ReadMapping) -> Result<(), Error> {
+        self.push(read.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Hand each result to a closure as it comes in.
+pub struct ReadCallback<F: FnMut(&ReadMapping)>(pub F);
+
+impl<F: FnMut(&ReadMapping)> ReadWriter for ReadCallback<F> {
+    fn write_read(&mut self, read: &ReadMapping) -> Result<(), Error> {
+        (self.0)(read);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Read back all the records written by a `BinReadWriter`.
+pub fn read_bin_mappings<R: io::Read>(mut reader: R) -> Result<Vec<ReadMapping>, Error> {
+    let mut reads = Vec::new();
+    loop {
+        match deserialize_from(&mut reader) {
+            Ok(read) => reads.push(read),
+            Err(err) => {
+                if let bincode::ErrorKind::Io(ref io_err) = *err {
+                    if io_err.kind() == io::ErrorKind::UnexpectedEof {
+                        break;
+                    }
+                }
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(reads)
+}
+
+/// Create the per-read output file in `outdir` for the chosen format.
+/// `positions` tells if the index has node positions, which SAM output needs.
+pub fn create_read_writer<'a, P: AsRef<Path>>(
+    format: ReadOutputFormat,
+    tx_names: &'a [String],
+    tx_lengths: &'a [usize],
+    positions: bool,
+    outdir: P,
+) -> Result<Box<dyn ReadWriter + 'a>, Error> {
+    let writer: Box<dyn ReadWriter + 'a> = match format {
+        ReadOutputFormat::Tsv => {
+            let outfile = BufWriter::new(open_file("reads.tsv", outdir)?);
+            Box::new(TsvReadWriter::new(outfile, Some(tx_names), positions)?)
+        }
+        ReadOutputFormat::TsvIds => {
+            let outfile = BufWriter::new(open_file("reads.tsv", outdir)?);
+            Box::new(TsvReadWriter::new(outfile, None, positions)?)
+        }
+        ReadOutputFormat::Bin => {
+            let outfile = BufWriter::new(open_file("reads.bin", outdir)?);
+            Box::new(BinReadWriter::new(outfile))
+        }
+        ReadOutputFormat::Sam => {
+            if !positions {
+                return Err(MappingError::IncompatibleIndex(
+                    "SAM read output needs an index built with --positions".to_string(),
+                )
+                .into());
+            }
+            let outfile = BufWriter::new(open_file("reads.sam", outdir)?);
+            Box::new(SamReadWriter::new(outfile, tx_names, tx_lengths)?)
+        }
+    };
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reads() -> Vec<ReadMapping> {
+        vec![
+            ReadMapping {
+                read_id: "read1".to_string(),
+                mapped: true,
+                eq_class: vec![0, 2],
+                coverage: 60,
+                strand: Some(Strand::Reverse),
+                positions: vec![TxPosition {
+                    tx_id: 0,
+                    pos: -2,
+                    strand: Strand::Reverse,
+                    read_len: 60,
+                    fragment_len: Some(250),
+                }],
+                too_short: false,
+            },
+            ReadMapping {
+                read_id: "read2".to_string(),
+                mapped: false,
+                eq_class: vec![],
+                coverage: 0,
+                strand: None,
+                positions: vec![],
+                too_short: false,
+            },
+            // mapped, without a position on its transcripts
+            ReadMapping {
+                read_id: "read3".to_string(),
+                mapped: true,
+                eq_class: vec![1],
+                coverage: 40,
+                strand: Some(Strand::Forward),
+                positions: vec![],
+                too_short: false,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_tsv_writer() -> Result<(), Error> {
+        let tx_names = vec!["tx0".to_string(), "tx1".to_string(), "tx2".to_string()];
+
+        let mut buf = Vec::new();
+        {
+            let mut writer = TsvReadWriter::new(&mut buf, Some(&tx_names[..]), false)?;
+            for read in reads() {
+                writer.write_read(&read)?;
+            }
+        }
+        let expected = "read_id\tmapped\tcoverage\tstrand\ttranscripts\n\
+                        read1\t1\t60\t-\ttx0,tx2\n\
+                        read2\t0\t0\t.\t\n\
+                        read3\t1\t40\t+\ttx1\n";
+        assert_eq!(String::from_utf8(buf)?, expected);
+
+        let mut buf = Vec::new();
+        {
+            let mut writer = TsvReadWriter::new(&mut buf, None, false)?;
+            writer.write_read(&reads()[0])?;
+        }
+        assert!(String::from_utf8(buf)?.ends_with("read1\t1\t60\t-\t0,2\n"));
+
+        let mut buf = Vec::new();
+        {
+            let mut writer = TsvReadWriter::new(&mut buf, Some(&tx_names[..]), true)?;
+            for read in reads() {
+                writer.write_read(&read)?;
+            }
+        }
+        let lines: Vec<String> = String::from_utf8(buf)?.lines().map(String::from).collect();
+        assert!(lines[0].ends_with("\ttranscripts\tpositions"));
+        assert_eq!(lines[1], "read1\t1\t60\t-\ttx0,tx2\ttx0:-2:-");
+        assert_eq!(lines[3], "read3\t1\t40\t+\ttx1\t");
+        Ok(())
+    }
+
+    #[test]
+    fn test_sam_writer() -> Result<(), Error> {
+        let tx_names = vec!["tx0".to_string(), "tx1".to_string(), "tx2".to_string()];
+
+        let mut buf = Vec::new();
+        {
+            let mut writer = SamReadWriter::new(&mut buf, &tx_names, &[100, 200, 300])?;
+            for read in reads() {
+                writer.write_read(&read)?;
+            }
+            writer.write_read(&reads()[0])?;
+        }
+
+        let sam = String::from_utf8(buf)?;
+        let lines: Vec<&str> = sam.lines().collect();
+        assert_eq!(lines[0], "@HD\tVN:1.6\tSO:unsorted");
+        assert_eq!(lines[2], "@SQ\tSN:tx1\tLN:200");
+        assert_eq!(
+            &lines[5..],
+            &[
+                "read1\t16\ttx0\t1\t0\t2S58M\t*\t0\t0\t*\t*\tNH:i:2\tZE:i:0\tZC:i:60\tZF:i:250",
+                "read2\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\tZC:i:0",
+                "read3\t0\ttx1\t0\t255\t*\t*\t0\t0\t*\t*\tNH:i:1\tZE:i:1\tZC:i:40",
+                "read1\t16\ttx0\t1\t0\t2S58M\t*\t0\t0\t*\t*\tNH:i:2\tZE:i:0\tZC:i:60\tZF:i:250",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bin_roundtrip() -> Result<(), Error> {
+        let mut buf = Vec::new();
+        {
+            let mut writer = BinReadWriter::new(&mut buf);
+            for read in reads() {
+                writer.write_read(&read)?;
+            }
+        }
+
+        assert_eq!(read_bin_mappings(&buf[..])?, reads());
+        Ok(())
+    }
+}
@@ -5,58 +5,95 @@ use std::fmt::Debug;
 use std::io::{self, Write};
 use std::path::Path;
 use std::sync::{mpsc, Arc, Mutex};
-use std::{self, fs::File, str};
+use std::{self, str};
 
 use bio::io::fastq;
-use boomphf::hashmap::NoKeyBoomHashMap;
 use crossbeam_utils::thread::scope;
 use debruijn::dna_string::DnaString;
 
 use debruijn::graph::DebruijnGraph;
 use debruijn::{Dir, Kmer, Mer, Vmer};
-use failure::Error;
+use failure::{format_err, Error};
 use log::info;
 use serde::{Deserialize, Serialize};
 
-use crate::config::{LEFT_EXTEND_FRACTION, READ_COVERAGE_THRESHOLD};
+use crate::annotation::{GeneInfo, TargetKind};
+use crate::config::{IndexConfig, LibraryType, MappingConfig};
 use crate::equiv_classes::EqClassIdType;
+use crate::error::MappingError;
+use crate::fragment_length::FragmentLengthDist;
+use crate::kmer_index::KmerIndex;
+use crate::output::{create_read_writer, ReadOutputFormat, ReadWriter};
+use crate::quant::EqClassCounts;
 use crate::utils;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Pseudoaligner<K: Kmer> {
+    // settings the index was built with
+    pub index_config: IndexConfig,
     pub dbg: DebruijnGraph<K, EqClassIdType>,
     pub eq_classes: Vec<Vec<u32>>,
-    pub dbg_index: NoKeyBoomHashMap<K, (u32, u32)>,
+    pub dbg_index: KmerIndex<K>,
     pub tx_names: Vec<String>,
+    pub tx_lengths: Vec<usize>,
     pub tx_gene_mapping: HashMap<String, String>,
+    // gene names and biotypes, filled in if the index was built with an
+    // annotation
+    pub gene_info: HashMap<String, GeneInfo>,
+    // kind of each target, all `Mature` unless the index holds introns
+    pub target_kinds: Vec<TargetKind>,
+    // occurrences of each graph node in the transcripts, indexed by node id;
+    // only present in indexes built with `IndexConfig::positions`
+    pub node_positions: Option<Vec<Vec<NodeTxPosition>>>,
 }
 
 impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
     pub fn new(
         dbg: DebruijnGraph<K, EqClassIdType>,
         eq_classes: Vec<Vec<u32>>,
-        dbg_index: NoKeyBoomHashMap<K, (u32, u32)>,
+        dbg_index: KmerIndex<K>,
         tx_names: Vec<String>,
+        tx_lengths: Vec<usize>,
         tx_gene_mapping: HashMap<String, String>,
+        index_config: IndexConfig,
     ) -> Pseudoaligner<K> {
+        let target_kinds = vec![TargetKind::Mature; tx_names.len()];
         Pseudoaligner {
+            index_config,
             dbg,
             eq_classes,
             dbg_index,
             tx_names,
+            tx_lengths,
             tx_gene_mapping,
+            gene_info: HashMap::new(),
+            target_kinds,
+            node_positions: None,
         }
     }
 
+    /// Human readable name of a gene, or its id if the name isn't known.
+    pub fn gene_name<'a>(&'a self, gene_id: &'a str) -> &'a str {
+        self.gene_info
+            .get(gene_id)
+            .and_then(|info| info.gene_name.as_ref())
+            .map_or(gene_id, |name| name.as_str())
+    }
+
     /// Pseudo-align `read_seq` and return a list of nodes that the read was aligned to
-    pub fn map_read_to_nodes(&self, read_seq: &DnaString, nodes: &mut Vec<usize>) -> Option<usize> {
+    pub fn map_read_to_nodes(
+        &self,
+        read_seq: &DnaString,
+        config: &MappingConfig,
+        nodes: &mut Vec<usize>,
+    ) -> Option<usize> {
         let read_length = read_seq.len();
         let mut read_coverage: usize = 0;
 
         // We're filling out nodes
         nodes.clear();
 
-        let left_extend_threshold = (LEFT_EXTEND_FRACTION * read_length as f64) as usize;
+        let left_extend_threshold = (config.left_extend_fraction * read_length as f64) as usize;
 
         let mut kmer_pos: usize = 0;
         let kmer_length = K::k();
@@ -80,16 +117,16 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
                         Some((nid, offset)) => {
                             // Verify that the kmer actually matches -- the MPHF can have false
                             // positives.
-                            let node = self.dbg.get_node(*nid as usize);
+                            let node = self.dbg.get_node(nid as usize);
                             let ref_seq_slice = node.sequence();
-                            let ref_kmer: K = ref_seq_slice.get_kmer(*offset as usize);
+                            let ref_kmer: K = ref_seq_slice.get_kmer(offset as usize);
 
                             if read_kmer == ref_kmer {
-                                return Some((*nid as usize, *offset as usize));
+                                return Some((nid as usize, offset as usize));
                             }
                         }
                     };
-                    *kmer_pos += 3;
+                    *kmer_pos += config.kmer_stride;
                 }
 
                 None
@@ -138,9 +175,9 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
 
                         // compare base by base
                         if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
-                            // Allowing 2-SNP
+                            // Allowing a few SNPs
                             seen_snp += 1;
-                            if seen_snp > 2 {
+                            if seen_snp > config.max_mismatches {
                                 premature_break = true;
                                 break;
                             }
@@ -219,9 +256,9 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
 
                         // compare base by base
                         if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
-                            // Allowing 2-SNP
+                            // Allowing a few SNPs
                             seen_snp += 1;
-                            if seen_snp > 2 {
+                            if seen_snp > config.max_mismatches {
                                 premature_break = true;
                                 break;
                             }
@@ -280,13 +317,7 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
         }
 
         if nodes.len() == 0 {
-            if read_coverage != 0 {
-                panic!(
-                    "Different read coverage {:?} than num of eqclasses {:?}",
-                    nodes.len(),
-                    read_coverage
-                );
-            }
+            debug_assert_eq!(read_coverage, 0, "read coverage without any node hit");
             //println!("lookups: {} -- no hit", kmer_lookups);
             None
         } else {
@@ -335,10 +366,14 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
     /// Pseudoalign the `read_seq` to the graph. Returns a tuple of the
     /// eqivalence class and the number of bases aligned on success
     /// or None is no alignment could be found.
-    pub fn map_read(&self, read_seq: &DnaString) -> Option<(Vec<u32>, usize)> {
+    pub fn map_read(
+        &self,
+        read_seq: &DnaString,
+        config: &MappingConfig,
+    ) -> Option<(Vec<u32>, usize)> {
         let mut nodes = Vec::new();
 
-        match self.map_read_to_nodes(read_seq, &mut nodes) {
+        match self.map_read_to_nodes(read_seq, config, &mut nodes) {
             Some(read_coverage) => {
                 let mut eq_class = Vec::new();
                 self.nodes_to_eq_class(&mut nodes, &mut eq_class);
@@ -347,6 +382,282 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
             None => None,
         }
     }
+
+    /// Pseudoalign `read_seq` in the orientations allowed by the library
+    /// type of `config`. Returns the equivalence class, the number of bases
+    /// aligned and the strand of the hit. If both orientations are tried the
+    /// better one is kept.
+    pub fn map_read_stranded(
+        &self,
+        read_seq: &DnaString,
+        config: &MappingConfig,
+    ) -> Option<(Vec<u32>, usize, Strand)> {
+        library_strands(config.library_type)
+            .iter()
+            .filter_map(|&strand| {
+                let read_data = match strand {
+                    Strand::Forward => self.map_read(read_seq, config),
+                    Strand::Reverse => self.map_read(&read_seq.rc(), config),
+                };
+                read_data.map(|(eq_class, coverage)| (eq_class, coverage, strand))
+            })
+            .fold(None, best_hit)
+    }
+
+    /// Pseudoalign both mates of a read pair and combine the results into a
+    /// single fragment-level hit. The mates are expected on opposite strands,
+    /// and the strand of the first mate is reported as the strand of the hit.
+    pub fn map_read_pair(
+        &self,
+        read_seq1: &DnaString,
+        read_seq2: &DnaString,
+        config: &MappingConfig,
+    ) -> Option<(Vec<u32>, usize, Strand)> {
+        library_strands(config.library_type)
+            .iter()
+            .filter_map(|&strand| {
+                let read_data = match strand {
+                    Strand::Forward => self.map_mates(read_seq1, &read_seq2.rc(), config),
+                    Strand::Reverse => self.map_mates(&read_seq1.rc(), read_seq2, config),
+                };
+                read_data.map(|(eq_class, coverage)| (eq_class, coverage, strand))
+            })
+            .fold(None, best_hit)
+    }
+
+    /// Combine the hits of two mates in transcript orientation. The
+    /// coverage threshold applies to each mate on its own: if both mates pass
+    /// it the equivalence classes are intersected and the coverage summed,
+    /// otherwise the better mate is used as is, and only counts as mapped if
+    /// it passes.
+    fn map_mates(
+        &self,
+        read_seq1: &DnaString,
+        read_seq2: &DnaString,
+        config: &MappingConfig,
+    ) -> Option<(Vec<u32>, usize)> {
+        let passes = |coverage: usize| coverage >= config.read_coverage_threshold;
+        match (
+            self.map_read(read_seq1, config),
+            self.map_read(read_seq2, config),
+        ) {
+            (Some((mut eq_class, coverage1)), Some((eq_class2, coverage2)))
+                if passes(coverage1) && passes(coverage2) =>
+            {
+                intersect(&mut eq_class, &eq_class2);
+                Some((eq_class, coverage1 + coverage2))
+            }
+            (Some(read_data1), Some(read_data2)) => {
+                if read_data1.1 >= read_data2.1 {
+                    Some(read_data1)
+                } else {
+                    Some(read_data2)
+                }
+            }
+            (Some(read_data), None) | (None, Some(read_data)) => Some(read_data),
+            (None, None) => None,
+        }
+    }
+
+    /// Graph node and node offset of `kmer`, verified against the node
+    /// sequence.
+    pub fn find_kmer(&self, kmer: &K) -> Option<(usize, usize)> {
+        let (node_id, offset) = self.dbg_index.get(kmer)?;
+        let node = self.dbg.get_node(node_id as usize);
+        let node_kmer: K = node.sequence().get_kmer(offset as usize);
+        if node_kmer == *kmer {
+            Some((node_id as usize, offset as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Positions of a mapped read on the transcripts of its equivalence
+    /// class. Empty unless the index holds node positions.
+    pub fn read_positions(&self, read_seq: &DnaString, read: &ReadMapping) -> Vec<TxPosition> {
+        let strand = match read.strand {
+            Some(strand) if self.node_positions.is_some() => strand,
+            _ => return Vec::new(),
+        };
+        let seq = match strand {
+            Strand::Forward => read_seq.clone(),
+            Strand::Reverse => read_seq.rc(),
+        };
+
+        self.locate(&seq, &read.eq_class)
+            .into_iter()
+            .map(|(tx_id, pos, node_strand)| TxPosition {
+                tx_id,
+                pos,
+                strand: strand.combine(node_strand),
+                read_len: read_seq.len() as u32,
+                fragment_len: None,
+            })
+            .collect()
+    }
+
+    /// Positions of the first mate of a mapped read pair, along with the
+    /// fragment length on the transcripts where both mates are located.
+    pub fn read_pair_positions(
+        &self,
+        read_seq1: &DnaString,
+        read_seq2: &DnaString,
+        read: &ReadMapping,
+    ) -> Vec<TxPosition> {
+        let strand = match read.strand {
+            Some(strand) if self.node_positions.is_some() => strand,
+            _ => return Vec::new(),
+        };
+        // both mates in transcript orientation, as in `map_read_pair`
+        let (mate1, mate2) = match strand {
+            Strand::Forward => (read_seq1.clone(), read_seq2.rc()),
+            Strand::Reverse => (read_seq1.rc(), read_seq2.clone()),
+        };
+        let len1 = mate1.len() as i32;
+        let len2 = mate2.len() as i32;
+        let mate2_hits = self.locate(&mate2, &read.eq_class);
+
+        self.locate(&mate1, &read.eq_class)
+            .into_iter()
+            .map(|(tx_id, pos1, node_strand)| {
+                let fragment_len =
+                    mate2_hits
+                        .iter()
+                        .find(|hit| hit.0 == tx_id)
+                        .map(|&(_, pos2, _)| {
+                            let start = pos1.min(pos2);
+                            let end = (pos1 + len1).max(pos2 + len2);
+                            (end - start) as u32
+                        });
+                TxPosition {
+                    tx_id,
+                    pos: pos1,
+                    strand: strand.combine(node_strand),
+                    read_len: len1 as u32,
+                    fragment_len,
+                }
+            })
+            .collect()
+    }
+
+    /// Start of `seq`, in transcript orientation, on each transcript of
+    /// `eq_class` that holds the node of its first kmer hit, along with the
+    /// orientation of that node on the transcript.
+    fn locate(&self, seq: &DnaString, eq_class: &[u32]) -> Vec<(u32, i32, Strand)> {
+        let node_positions = match &self.node_positions {
+            Some(node_positions) => node_positions,
+            None => return Vec::new(),
+        };
+        if seq.len() < K::k() {
+            return Vec::new();
+        }
+        let hit = (0..=seq.len() - K::k()).find_map(|read_pos| {
+            self.find_kmer(&seq.get_kmer(read_pos))
+                .map(|(node_id, offset)| (read_pos as i32, node_id, offset as i32))
+        });
+        let (read_pos, node_id, offset) = match hit {
+            Some(hit) => hit,
+            None => return Vec::new(),
+        };
+
+        let k = K::k() as i32;
+        let len = seq.len() as i32;
+        eq_class
+            .iter()
+            .filter_map(|&tx_id| {
+                node_positions[node_id]
+                    .iter()
+                    .find(|p| p.tx_id == tx_id)
+                    .map(|p| {
+                        let pos = match p.strand {
+                            Strand::Forward => p.anchor + offset - read_pos,
+                            // the read kmer is the reverse complement of the
+                            // transcript kmer at `anchor - offset`
+                            Strand::Reverse => p.anchor - offset + k + read_pos - len,
+                        };
+                        (tx_id, pos, p.strand)
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Strand of the transcripts that a read, or the first mate of a read pair,
+/// was found on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
+
+impl Strand {
+    pub fn symbol(self) -> char {
+        match self {
+            Strand::Forward => '+',
+            Strand::Reverse => '-',
+        }
+    }
+
+    pub fn reverse(self) -> Strand {
+        match self {
+            Strand::Forward => Strand::Reverse,
+            Strand::Reverse => Strand::Forward,
+        }
+    }
+
+    /// Orientation of a sequence found on `other` relative to a sequence on
+    /// `self`.
+    pub fn combine(self, other: Strand) -> Strand {
+        match other {
+            Strand::Forward => self,
+            Strand::Reverse => self.reverse(),
+        }
+    }
+}
+
+/// Occurrence of a graph node in a transcript. For a node in transcript
+/// orientation the kmer at node offset `o` starts at transcript position
+/// `anchor + o`, for a reverse complemented node at `anchor - o`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NodeTxPosition {
+    pub tx_id: u32,
+    pub anchor: i32,
+    pub strand: Strand,
+}
+
+/// Implied position of a read on one of its compatible transcripts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TxPosition {
+    pub tx_id: u32,
+    // 0-based start of the read on the transcript, negative for reads
+    // hanging off the transcript start
+    pub pos: i32,
+    // orientation of the read relative to the transcript
+    pub strand: Strand,
+    pub read_len: u32,
+    // span of both mates of a read pair on the transcript
+    pub fragment_len: Option<u32>,
+}
+
+/// Read orientations to try for a library type.
+fn library_strands(library_type: LibraryType) -> &'static [Strand] {
+    match library_type {
+        LibraryType::Forward => &[Strand::Forward],
+        LibraryType::Reverse => &[Strand::Reverse],
+        LibraryType::Unstranded => &[Strand::Forward, Strand::Reverse],
+    }
+}
+
+/// Pick the better of two hits: a non-empty equivalence class first, then
+/// the higher coverage. Ties go to the earlier hit.
+fn best_hit(
+    best: Option<(Vec<u32>, usize, Strand)>,
+    hit: (Vec<u32>, usize, Strand),
+) -> Option<(Vec<u32>, usize, Strand)> {
+    match best {
+        Some(best) if (!best.0.is_empty(), best.1) >= (!hit.0.is_empty(), hit.1) => Some(best),
+        _ => Some(hit),
+    }
 }
 
 /// Compute the intersection of v1 and v2 inplace on top of v1
@@ -382,55 +693,325 @@ pub fn intersect<T: Eq + Ord>(v1: &mut Vec<T>, v2: &[T]) {
     v1.truncate(fill_idx1);
 }
 
-pub fn process_reads<K: Kmer + Sync + Send, P: AsRef<Path> + Debug>(
-    reader: fastq::Reader<File>,
+/// Pseudoalignment result of a single read, or read pair.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReadMapping {
+    pub read_id: String,
+    // whether the read passed the mapping criteria
+    pub mapped: bool,
+    pub eq_class: Vec<u32>,
+    // number of read bases covered by the pseudoalignment
+    pub coverage: usize,
+    // strand of the hit, None if the read didn't align
+    pub strand: Option<Strand>,
+    // positions on the transcripts of `eq_class`, see
+    // `Pseudoaligner::read_positions`
+    pub positions: Vec<TxPosition>,
+    // the read, or both mates of a pair, is shorter than the kmer length
+    pub too_short: bool,
+}
+
+impl ReadMapping {
+    /// Decide whether a pseudoalignment passes the mapping criteria and
+    /// package it up with the read name.
+    pub fn new(
+        read_id: &str,
+        read_data: Option<(Vec<u32>, usize, Strand)>,
+        config: &MappingConfig,
+    ) -> ReadMapping {
+        let (eq_class, coverage, strand) = match read_data {
+            Some((eq_class, coverage, strand)) => (eq_class, coverage, Some(strand)),
+            None => (Vec::new(), 0, None),
+        };
+        let mapped = coverage >= config.read_coverage_threshold && !eq_class.is_empty();
+
+        ReadMapping {
+            read_id: read_id.to_owned(),
+            mapped,
+            eq_class,
+            coverage,
+            strand,
+            positions: Vec::new(),
+            too_short: false,
+        }
+    }
+}
+
+/// Sequence of a FASTQ record, failing on a malformed record.
+pub(crate) fn record_to_dna_string(record: &fastq::Record) -> Result<DnaString, Error> {
+    record
+        .check()
+        .map_err(|err| MappingError::Parse(format!("FASTQ record {}: {}", record.id(), err)))?;
+    let dna_string = str::from_utf8(record.seq())
+        .map_err(|err| MappingError::Parse(format!("FASTQ record {}: {}", record.id(), err)))?;
+    Ok(DnaString::from_dna_string(dna_string))
+}
+
+/// Totals of a mapping run.
+#[derive(Debug, Default)]
+pub struct MappingSummary {
+    pub num_reads: usize,
+    pub num_mapped: usize,
+    // reads hitting the index without a transcript common to all hits
+    pub num_empty_intersections: usize,
+    // reads shorter than the kmer length
+    pub num_too_short: usize,
+    // read bases covered, summed over the mapped reads
+    pub total_coverage: usize,
+    // equivalence classes of the mapped reads
+    pub eq_counts: EqClassCounts,
+    // fragment lengths of the read pairs mapped to a single transcript
+    pub fragment_lengths: FragmentLengthDist,
+    // single-cell reads without a cell barcode and UMI, which aren't counted
+    pub num_untagged: usize,
+}
+
+impl MappingSummary {
+    /// Read bases covered per mapped read.
+    pub fn mean_coverage(&self) -> f64 {
+        if self.num_mapped == 0 {
+            0.0
+        } else {
+            self.total_coverage as f64 / self.num_mapped as f64
+        }
+    }
+}
+
+/// Map the reads of a FASTQ file, writing the per-read results to `outdir`.
+pub fn process_reads<K, R, P>(
+    reader: fastq::Reader<R>,
     index: &Pseudoaligner<K>,
+    config: &MappingConfig,
     outdir: P,
     num_threads: usize,
-) -> Result<(), Error> {
+    read_output: ReadOutputFormat,
+) -> Result<MappingSummary, Error>
+where
+    K: Kmer + Sync + Send,
+    R: io::Read + Send,
+    P: AsRef<Path> + Debug,
+{
+    process_records(
+        reader.records(),
+        index,
+        config,
+        outdir,
+        num_threads,
+        read_output,
+    )
+}
+
+/// Map reads pulled from any source of FASTQ records, such as an in-memory
+/// collection or a network stream, writing the per-read results to `outdir`.
+pub fn process_records<K, I, P>(
+    records: I,
+    index: &Pseudoaligner<K>,
+    config: &MappingConfig,
+    outdir: P,
+    num_threads: usize,
+    read_output: ReadOutputFormat,
+) -> Result<MappingSummary, Error>
+where
+    K: Kmer + Sync + Send,
+    I: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+    P: AsRef<Path> + Debug,
+{
+    info!("Output directory: {:?}", outdir);
+    let mut writer = create_read_writer(
+        read_output,
+        &index.tx_names,
+        &index.tx_lengths,
+        index.node_positions.is_some(),
+        &outdir,
+    )?;
+    map_records(records, index, config, num_threads, writer.as_mut())
+}
+
+/// Map reads on `num_threads` threads and hand each result to `writer`,
+/// e.g. a `Vec<ReadMapping>` to collect them or a `ReadCallback`.
+pub fn map_records<K, I>(
+    records: I,
+    index: &Pseudoaligner<K>,
+    config: &MappingConfig,
+    num_threads: usize,
+    writer: &mut dyn ReadWriter,
+) -> Result<MappingSummary, Error>
+where
+    K: Kmer + Sync + Send,
+    I: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+{
     info!("Done Reading index");
     info!("Starting Multi-threaded Mapping");
+
+    let atomic_reader = Arc::new(Mutex::new(records));
+
+    let next_read = || {
+        utils::get_next_record(&atomic_reader).map(|result_record| -> Result<_, Error> {
+            let record = result_record?;
+            let seq = record_to_dna_string(&record)?;
+            let mut read =
+                ReadMapping::new(record.id(), index.map_read_stranded(&seq, config), config);
+            read.positions = index.read_positions(&seq, &read);
+            read.too_short = seq.len() < K::k();
+            Ok((read, ()))
+        })
+    };
+
+    let summary = map_reads_parallel(next_read, |read, ()| writer.write_read(read), num_threads)?;
+    writer.flush()?;
+    Ok(summary)
+}
+
+/// Map paired-end reads, reading R1 and R2 in lockstep. One result is
+/// reported per fragment, named after the first mate.
+pub fn process_paired_reads<K, R, P>(
+    reader1: fastq::Reader<R>,
+    reader2: fastq::Reader<R>,
+    index: &Pseudoaligner<K>,
+    config: &MappingConfig,
+    outdir: P,
+    num_threads: usize,
+    read_output: ReadOutputFormat,
+) -> Result<MappingSummary, Error>
+where
+    K: Kmer + Sync + Send,
+    R: io::Read + Send,
+    P: AsRef<Path> + Debug,
+{
+    process_paired_records(
+        reader1.records(),
+        reader2.records(),
+        index,
+        config,
+        outdir,
+        num_threads,
+        read_output,
+    )
+}
+
+/// Paired-end version of `process_records`, taking one record source per mate.
+pub fn process_paired_records<K, I1, I2, P>(
+    records1: I1,
+    records2: I2,
+    index: &Pseudoaligner<K>,
+    config: &MappingConfig,
+    outdir: P,
+    num_threads: usize,
+    read_output: ReadOutputFormat,
+) -> Result<MappingSummary, Error>
+where
+    K: Kmer + Sync + Send,
+    I1: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+    I2: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+    P: AsRef<Path> + Debug,
+{
     info!("Output directory: {:?}", outdir);
+    let mut writer = create_read_writer(
+        read_output,
+        &index.tx_names,
+        &index.tx_lengths,
+        index.node_positions.is_some(),
+        &outdir,
+    )?;
+    map_paired_records(
+        records1,
+        records2,
+        index,
+        config,
+        num_threads,
+        writer.as_mut(),
+    )
+}
+
+/// Paired-end version of `map_records`, taking one record source per mate.
+pub fn map_paired_records<K, I1, I2>(
+    records1: I1,
+    records2: I2,
+    index: &Pseudoaligner<K>,
+    config: &MappingConfig,
+    num_threads: usize,
+    writer: &mut dyn ReadWriter,
+) -> Result<MappingSummary, Error>
+where
+    K: Kmer + Sync + Send,
+    I1: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+    I2: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+{
+    info!("Done Reading index");
+    info!("Starting Multi-threaded Paired-end Mapping");
+
+    let atomic_reader = Arc::new(Mutex::new((records1, records2)));
+
+    let next_read = || {
+        utils::get_next_record_pair(&atomic_reader).map(|result_pair| -> Result<_, Error> {
+            let (record1, record2) = result_pair?;
+            let seq1 = record_to_dna_string(&record1)?;
+            let seq2 = record_to_dna_string(&record2)?;
+            let mut read = ReadMapping::new(
+                record1.id(),
+                index.map_read_pair(&seq1, &seq2, config),
+                config,
+            );
+            read.positions = index.read_pair_positions(&seq1, &seq2, &read);
+            read.too_short = seq1.len() < K::k() && seq2.len() < K::k();
+            Ok((read, ()))
+        })
+    };
+
+    let summary = map_reads_parallel(next_read, |read, ()| writer.write_read(read), num_threads)?;
+    writer.flush()?;
+    Ok(summary)
+}
+
+/// Run `num_threads` workers that repeatedly pull a mapped read, along with
+/// any extra per-read data, from `next_read` until it is exhausted. Each
+/// result is handed to `consume` on the calling thread, and the mapped reads
+/// are tallied. The first error hit by any worker is returned once all
+/// workers are done. `num_threads` must be at least 1.
+pub(crate) fn map_reads_parallel<X, F, C>(
+    next_read: F,
+    mut consume: C,
+    num_threads: usize,
+) -> Result<MappingSummary, Error>
+where
+    X: Send,
+    F: Fn() -> Option<Result<(ReadMapping, X), Error>> + Sync,
+    C: FnMut(&ReadMapping, X) -> Result<(), Error>,
+{
+    if num_threads == 0 {
+        return Err(format_err!("mapping needs at least one thread"));
+    }
 
     let (tx, rx) = mpsc::sync_channel(num_threads);
-    let atomic_reader = Arc::new(Mutex::new(reader.records()));
+    let next_read = &next_read;
+    let mut first_error = None;
+    let mut summary = MappingSummary::default();
 
     info!("Spawning {} threads for Mapping.\n", num_threads);
     scope(|scope| {
         for _ in 0..num_threads {
             let tx = tx.clone();
-            let reader = Arc::clone(&atomic_reader);
 
             scope.spawn(move |_| {
                 loop {
                     // If work is available, do that work.
-                    match utils::get_next_record(&reader) {
-                        Some(result_record) => {
-                            let record = match result_record {
-                                Ok(record) => record,
-                                Err(err) => panic!("Error {:?} in reading fastq", err),
-                            };
-
-                            let dna_string = str::from_utf8(record.seq()).unwrap();
-                            let seq = DnaString::from_dna_string(dna_string);
-                            let read_data = index.map_read(&seq);
-
-                            let wrapped_read_data = match read_data {
-                                Some((eq_class, coverage)) => {
-                                    if coverage >= READ_COVERAGE_THRESHOLD && eq_class.is_empty() {
-                                        Some((true, record.id().to_owned(), eq_class, coverage))
-                                    } else {
-                                        Some((false, record.id().to_owned(), eq_class, coverage))
-                                    }
-                                }
-                                None => Some((false, record.id().to_owned(), Vec::new(), 0)),
-                            };
-
-                            tx.send(wrapped_read_data).expect("Could not send data!");
+                    // a failed send means the receiver is gone, so the
+                    // worker stops rather than mapping reads nobody reads
+                    match next_read() {
+                        Some(Ok(read_data)) => {
+                            if tx.send(Some(Ok(read_data))).is_err() {
+                                break;
+                            }
+                        }
+                        Some(Err(err)) => {
+                            // report the error, then retire this worker
+                            let _ = tx.send(Some(Err(err)));
+                            let _ = tx.send(None);
+                            break;
                         }
                         None => {
                             // send None to tell receiver that the queue ended
-                            tx.send(None).expect("Could not send data!");
+                            let _ = tx.send(None);
                             break;
                         }
                     }; //end-match
@@ -438,40 +1019,57 @@ pub fn process_reads<K: Kmer + Sync + Send, P: AsRef<Path> + Debug>(
             }); //end-scope
         } // end-for
 
-        let mut read_counter: usize = 0;
-        let mut mapped_read_counter: usize = 0;
         let mut dead_thread_count = 0;
 
         for eq_class in rx.iter() {
             match eq_class {
                 None => {
                     dead_thread_count += 1;
+                    // each worker sends `None` after its last read, so
+                    // nothing is left once all of them are done
                     if dead_thread_count == num_threads {
                         drop(tx);
-                        // can't continue with a flag check
-                        // weird Rusty way !
-                        // Consume whatever is remaining
-                        // Not worrying about counters; hunch is their
-                        // should be less
-                        for eq_class in rx.iter() {
-                            eq_class.map_or((), |eq_class| eprintln!("{:?}", eq_class));
-                        }
                         break;
                     }
                 }
-                Some(read_data) => {
-                    println!("{:?}", read_data);
+                Some(Err(err)) => {
+                    if first_error.is_none() {
+                        first_error = Some(err);
+                    }
+                }
+                Some(Ok((read, extra))) => {
+                    if let Err(err) = consume(&read, extra) {
+                        if first_error.is_none() {
+                            first_error = Some(err);
+                        }
+                    }
+
+                    if read.too_short {
+                        summary.num_too_short += 1;
+                    } else if read.strand.is_some() && read.eq_class.is_empty() {
+                        summary.num_empty_intersections += 1;
+                    }
 
-                    if read_data.0 {
-                        mapped_read_counter += 1;
+                    if read.mapped {
+                        summary.total_coverage += read.coverage;
+                        if read.eq_class.len() == 1 {
+                            for position in &read.positions {
+                                if let Some(fragment_len) = position.fragment_len {
+                                    summary.fragment_lengths.add(fragment_len);
+                                }
+                            }
+                        }
+                        summary.num_mapped += 1;
+                        *summary.eq_counts.entry(read.eq_class).or_insert(0) += 1;
                     }
 
-                    read_counter += 1;
-                    if read_counter % 1_000_000 == 0 {
-                        let frac_mapped = mapped_read_counter as f32 * 100.0 / read_counter as f32;
+                    summary.num_reads += 1;
+                    if summary.num_reads % 1_000_000 == 0 {
+                        let frac_mapped =
+                            summary.num_mapped as f32 * 100.0 / summary.num_reads as f32;
                         eprint!(
                             "\rDone Mapping {} reads w/ Rate: {}",
-                            read_counter, frac_mapped
+                            summary.num_reads, frac_mapped
                         );
                         io::stderr().flush().expect("Could not flush stdout");
                     }
@@ -479,16 +1077,23 @@ pub fn process_reads<K: Kmer + Sync + Send, P: AsRef<Path> + Debug>(
             } // end-match
         } // end-for
     })
-    .unwrap(); //end crossbeam
+    .map_err(|_| format_err!("a mapping thread panicked"))?; //end crossbeam
 
     eprintln!();
+    if let Some(err) = first_error {
+        return Err(err);
+    }
+
     info!("Done Mapping Reads");
-    Ok(())
+    Ok(summary)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::build_index::build_index;
+    use crate::config::KmerType;
+    use crate::test_data::{first_transcript, small_index, small_positions_index, test_dir};
     use proptest::collection::vec;
     use proptest::prelude::*;
     use proptest::proptest;
@@ -543,6 +1148,317 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_map_read_pair() -> Result<(), Error> {
+        let index = small_index();
+        let tx = first_transcript();
+        let mate1 = DnaString::from_acgt_bytes(&tx[0..100]);
+        // the second mate is sequenced from the opposite strand
+        let mate2 = DnaString::from_acgt_bytes(&tx[300..400]).rc();
+
+        let config = MappingConfig::default();
+        let (eq_class, coverage, strand) = index.map_read_pair(&mate1, &mate2, &config).unwrap();
+        assert!(eq_class.contains(&0));
+        assert_eq!(coverage, 200);
+        assert_eq!(strand, Strand::Forward);
+
+        let unmappable = DnaString::from_acgt_bytes(&tx[0..10]);
+        let (single_eq_class, _) = index.map_read(&mate1, &config).unwrap();
+        let (eq_class, _, _) = index.map_read_pair(&mate1, &unmappable, &config).unwrap();
+        assert_eq!(eq_class, single_eq_class);
+
+        let reverse_config = MappingConfig {
+            library_type: LibraryType::Reverse,
+            ..MappingConfig::default()
+        };
+        let (eq_class, coverage, strand) = index
+            .map_read_pair(&mate2, &mate1, &reverse_config)
+            .unwrap();
+        assert!(eq_class.contains(&0));
+        assert_eq!(coverage, 200);
+        assert_eq!(strand, Strand::Reverse);
+
+        // each mate must pass the coverage threshold on its own
+        let strict_config = MappingConfig {
+            read_coverage_threshold: 150,
+            ..MappingConfig::default()
+        };
+        let read_data = index.map_read_pair(&mate1, &mate2, &strict_config);
+        let (_, coverage, _) = read_data.clone().unwrap();
+        assert_eq!(coverage, 100);
+        assert!(!ReadMapping::new("pair", read_data, &strict_config).mapped);
+        Ok(())
+    }
+
+    // a random sequence, whose graph is a single node
+    fn random_seq(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b"ACGT"[(state >> 32) as usize % 4]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_max_mismatches() -> Result<(), Error> {
+        let seq = random_seq(200);
+        let tx_names = vec!["tx".to_string()];
+        let tx_gene_map = vec![("tx".to_string(), "gene".to_string())]
+            .into_iter()
+            .collect();
+        let seqs = vec![DnaString::from_acgt_bytes(&seq)];
+        let index: Pseudoaligner<KmerType> =
+            build_index(&seqs, &tx_names, &tx_gene_map, &IndexConfig::default(), 1)?;
+
+        // 3 SNPs after the first kmer, too close together for a later exact
+        // kmer to pick the read up again
+        let mut read = seq[50..110].to_vec();
+        for &pos in &[25, 40, 55] {
+            read[pos] = match read[pos] {
+                b'A' => b'C',
+                b'C' => b'G',
+                b'G' => b'T',
+                _ => b'A',
+            };
+        }
+        let read = DnaString::from_acgt_bytes(&read);
+
+        // only fully covered reads count as mapped
+        let config = |max_mismatches| MappingConfig {
+            max_mismatches,
+            read_coverage_threshold: 60,
+            ..MappingConfig::default()
+        };
+
+        let lenient = config(3);
+        let (eq_class, coverage) = index.map_read(&read, &lenient).unwrap();
+        assert_eq!(eq_class, vec![0]);
+        assert_eq!(coverage, 60);
+        let read_data = index.map_read_stranded(&read, &lenient);
+        assert!(ReadMapping::new("read", read_data, &lenient).mapped);
+
+        let strict = config(2);
+        let (_, coverage) = index.map_read(&read, &strict).unwrap();
+        assert_eq!(coverage, 55);
+        let read_data = index.map_read_stranded(&read, &strict);
+        assert!(!ReadMapping::new("read", read_data, &strict).mapped);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kmer_stride() {
+        let index = small_index();
+        let tx = first_transcript();
+        let read = DnaString::from_acgt_bytes(&tx[100..200]);
+
+        let every_kmer = MappingConfig {
+            kmer_stride: 1,
+            ..MappingConfig::default()
+        };
+        let every_third_kmer = MappingConfig {
+            kmer_stride: 3,
+            ..MappingConfig::default()
+        };
+        let (eq_class, coverage) = index.map_read(&read, &every_kmer).unwrap();
+        assert!(eq_class.contains(&0));
+        assert_eq!(coverage, 100);
+        assert_eq!(
+            index.map_read(&read, &every_third_kmer),
+            Some((eq_class, coverage))
+        );
+    }
+
+    #[test]
+    fn test_read_positions() -> Result<(), Error> {
+        let index = small_positions_index();
+        let tx = first_transcript();
+        let read = DnaString::from_acgt_bytes(&tx[50..150]);
+        let config = MappingConfig::default();
+        let mapping = ReadMapping::new("read", index.map_read_stranded(&read, &config), &config);
+        let position = index
+            .read_positions(&read, &mapping)
+            .into_iter()
+            .find(|p| p.tx_id == 0)
+            .unwrap();
+        assert_eq!(position.pos, 50);
+        assert_eq!(position.strand, Strand::Forward);
+        assert_eq!(position.read_len, 100);
+
+        let mate2 = DnaString::from_acgt_bytes(&tx[250..350]).rc();
+        let mapping =
+            ReadMapping::new("pair", index.map_read_pair(&read, &mate2, &config), &config);
+        let position = index
+            .read_pair_positions(&read, &mate2, &mapping)
+            .into_iter()
+            .find(|p| p.tx_id == 0)
+            .unwrap();
+        assert_eq!(position.pos, 50);
+        assert_eq!(position.fragment_len, Some(300));
+
+        let reverse = MappingConfig {
+            library_type: LibraryType::Reverse,
+            ..MappingConfig::default()
+        };
+        let rc_read = read.rc();
+        let mapping = ReadMapping::new("rc", index.map_read_stranded(&rc_read, &reverse), &reverse);
+        let position = index
+            .read_positions(&rc_read, &mapping)
+            .into_iter()
+            .find(|p| p.tx_id == 0)
+            .unwrap();
+        assert_eq!(position.pos, 50);
+        assert_eq!(position.strand, Strand::Reverse);
+
+        assert!(small_index().read_positions(&read, &mapping).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_library_type() -> Result<(), Error> {
+        let index = small_index();
+        let tx = first_transcript();
+        let read = DnaString::from_acgt_bytes(&tx[100..200]);
+        let rc_read = read.rc();
+
+        let forward = MappingConfig::default();
+        let (eq_class, _, strand) = index.map_read_stranded(&read, &forward).unwrap();
+        assert!(eq_class.contains(&0));
+        assert_eq!(strand, Strand::Forward);
+        let rc_hit = index.map_read_stranded(&rc_read, &forward);
+        assert!(rc_hit.map_or(true, |(eq_class, _, _)| !eq_class.contains(&0)));
+
+        let reverse = MappingConfig {
+            library_type: LibraryType::Reverse,
+            ..MappingConfig::default()
+        };
+        let (eq_class, _, strand) = index.map_read_stranded(&rc_read, &reverse).unwrap();
+        assert!(eq_class.contains(&0));
+        assert_eq!(strand, Strand::Reverse);
+
+        let unstranded = MappingConfig {
+            library_type: LibraryType::Unstranded,
+            ..MappingConfig::default()
+        };
+        for (seq, expected) in &[(&read, Strand::Forward), (&rc_read, Strand::Reverse)] {
+            let (eq_class, coverage, strand) = index.map_read_stranded(seq, &unstranded).unwrap();
+            assert!(eq_class.contains(&0));
+            assert_eq!(coverage, 100);
+            assert_eq!(strand, *expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_in_memory_reads() -> Result<(), Error> {
+        let index = small_index();
+        let tx = first_transcript();
+        let read_seq = &tx[100..200];
+        let qual = vec![b'I'; read_seq.len()];
+
+        let outdir = test_dir("in_memory_reads");
+
+        // FASTQ text from memory
+        let fastq_text = format!(
+            "@read1\n{}\n+\n{}\n@read2\nACGTACGTAC\n+\nIIIIIIIIII\n",
+            str::from_utf8(read_seq)?,
+            str::from_utf8(&qual)?
+        );
+        let reader = fastq::Reader::new(io::Cursor::new(fastq_text.into_bytes()));
+        let config = MappingConfig::default();
+        let summary = process_reads(reader, &index, &config, &outdir, 2, ReadOutputFormat::Tsv)?;
+
+        assert_eq!(summary.num_reads, 2);
+        assert_eq!(summary.num_mapped, 1);
+        assert_eq!(summary.eq_counts.values().sum::<u32>(), 1);
+        assert!(summary
+            .eq_counts
+            .keys()
+            .all(|eq_class| eq_class.contains(&0)));
+
+        // already parsed records, with the results collected in memory
+        let reads = vec![fastq::Record::with_attrs("read1", None, read_seq, &qual)];
+        let mut mappings: Vec<ReadMapping> = Vec::new();
+        let summary = map_records(reads.into_iter().map(Ok), &index, &config, 1, &mut mappings)?;
+
+        assert_eq!(summary.num_mapped, 1);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].read_id, "read1");
+        assert!(mappings[0].mapped);
+        assert_eq!(mappings[0].strand, Some(Strand::Forward));
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_reads_parallel() -> Result<(), Error> {
+        let config = MappingConfig::default();
+        let read_ids = Mutex::new(vec!["read1", "read2", "read3"]);
+        let next_read = || {
+            let read_id = read_ids.lock().unwrap().pop()?;
+            let read = ReadMapping::new(read_id, Some((vec![0], 40, Strand::Forward)), &config);
+            Some(Ok((read, ())))
+        };
+
+        let mut num_consumed = 0;
+        let summary = map_reads_parallel(
+            next_read,
+            |_, _| {
+                num_consumed += 1;
+                Ok(())
+            },
+            2,
+        )?;
+        assert_eq!(summary.num_reads, 3);
+        assert_eq!(summary.num_mapped, 3);
+        assert_eq!(num_consumed, 3);
+
+        // no workers would ever pull a read
+        let no_reads = || None::<Result<(ReadMapping, ()), Error>>;
+        assert!(map_reads_parallel(no_reads, |_, _| Ok(()), 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sam_output() -> Result<(), Error> {
+        let tx = first_transcript();
+        let read_seq = &tx[100..200];
+        let fastq_text = format!(
+            "@read1\n{}\n+\n{}\n",
+            str::from_utf8(read_seq)?,
+            str::from_utf8(&vec![b'I'; read_seq.len()])?
+        );
+        let config = MappingConfig::default();
+
+        // a default index has no positions to report
+        let outdir = test_dir("sam_default_index");
+        let reader = fastq::Reader::new(io::Cursor::new(fastq_text.clone().into_bytes()));
+        let res = process_reads(
+            reader,
+            small_index(),
+            &config,
+            &outdir,
+            1,
+            ReadOutputFormat::Sam,
+        );
+        assert!(res.is_err());
+
+        let outdir = test_dir("sam_positions_index");
+        let reader = fastq::Reader::new(io::Cursor::new(fastq_text.into_bytes()));
+        let index = small_positions_index();
+        process_reads(reader, index, &config, &outdir, 1, ReadOutputFormat::Sam)?;
+        let sam = std::fs::read_to_string(outdir.join("reads.sam"))?;
+        let primary = sam.lines().find(|l| l.starts_with("read1\t")).unwrap();
+        let fields: Vec<&str> = primary.split('\t').collect();
+        assert_eq!(fields[1], "0");
+        assert_eq!(fields[2], index.tx_names[0]);
+        assert_eq!(fields[3], "101");
+        assert_eq!(fields[5], "100M");
+        Ok(())
+    }
+
     proptest! {
         #![proptest_config(ProptestConfig { cases: 1000, .. ProptestConfig::default()})]
         #[test]
@@ -0,0 +1,506 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Transcript abundance estimation from equivalence class counts.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use debruijn::Kmer;
+use failure::Error;
+use itertools::Itertools;
+use log::info;
+
+use crate::annotation::TargetKind;
+use crate::config::{
+    EM_ALPHA_LIMIT, EM_MAX_ROUNDS, EM_MIN_ROUNDS, EM_TOLERANCE, MEAN_FRAGMENT_LENGTH, VBEM_PRIOR,
+};
+use crate::error::MappingError;
+use crate::fragment_length::FragmentLengthDist;
+use crate::pseudoaligner::Pseudoaligner;
+use crate::utils::open_file;
+
+/// Number of reads observed for each equivalence class.
+pub type EqClassCounts = HashMap<Vec<u32>, u32>;
+
+const ABUNDANCE_HEADER_STRING: &'static str = "target_id\tlength\teff_length\test_counts\ttpm\n";
+const EQ_COUNTS_HEADER_STRING: &'static str = "transcripts\tcount\n";
+const GENE_COUNTS_HEADER_STRING: &'static str =
+    "gene_id\tunique_counts\tambiguous_counts\tcounts\n";
+
+/// Write the equivalence class counts to `eq_class_counts.tsv`, one class per
+/// line as comma-separated transcript ids and the number of reads.
+pub fn write_eq_counts_tsv<P: AsRef<Path>>(
+    eq_counts: &EqClassCounts,
+    outdir: P,
+) -> Result<(), Error> {
+    let mut outfile = open_file("eq_class_counts.tsv", outdir)?;
+
+    outfile.write_all(EQ_COUNTS_HEADER_STRING.as_bytes())?;
+
+    let mut eq_classes: Vec<_> = eq_counts.iter().collect();
+    eq_classes.sort();
+    for (eq_class, count) in eq_classes {
+        write!(outfile, "{}\t{}\n", eq_class.iter().join(","), count)?;
+    }
+
+    Ok(())
+}
+
+/// Load equivalence class counts written by `write_eq_counts_tsv`.
+pub fn read_eq_counts_tsv<P: AsRef<Path>>(filename: P) -> Result<EqClassCounts, Error> {
+    let file = File::open(&filename).map_err(|err| MappingError::io(filename.as_ref(), err))?;
+    let reader = BufReader::new(file);
+    let mut eq_counts = EqClassCounts::new();
+
+    for (line_num, line) in reader.lines().enumerate().skip(1) {
+        let line = line?;
+        let malformed = || {
+            MappingError::Parse(format!(
+                "malformed equivalence class count on line {}: {}",
+                line_num + 1,
+                line
+            ))
+        };
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 2 {
+            return Err(malformed().into());
+        }
+
+        let mut eq_class = Vec::new();
+        for tx_id in fields[0].split(',').filter(|t| !t.is_empty()) {
+            eq_class.push(tx_id.parse::<u32>().map_err(|_| malformed())?);
+        }
+        let count = fields[1].parse::<u32>().map_err(|_| malformed())?;
+        eq_counts.insert(eq_class, count);
+    }
+
+    Ok(eq_counts)
+}
+
+/// Estimated abundance of every transcript in the index.
+#[derive(Debug)]
+pub struct Abundances {
+    pub eff_lengths: Vec<f64>,
+    pub est_counts: Vec<f64>,
+}
+
+impl Abundances {
+    /// Transcripts per million for each transcript. Intron targets are
+    /// left out and get 0.
+    pub fn tpm(&self, target_kinds: &[TargetKind]) -> Vec<f64> {
+        let rates: Vec<f64> = self
+            .est_counts
+            .iter()
+            .zip(self.eff_lengths.iter())
+            .zip(target_kinds)
+            .map(|((count, eff_len), kind)| match kind {
+                TargetKind::Mature => count / eff_len,
+                TargetKind::Intron => 0.0,
+            })
+            .collect();
+        let total: f64 = rates.iter().sum();
+
+        if total > 0.0 {
+            rates.iter().map(|rate| rate * 1e6 / total).collect()
+        } else {
+            vec![0.0; rates.len()]
+        }
+    }
+}
+
+/// Run the abundance estimation on the equivalence class counts of a
+/// mapping run. The effective transcript lengths come from `fragment_lengths`
+/// if given, otherwise from a fixed `MEAN_FRAGMENT_LENGTH`.
+pub fn quantify<K: Kmer>(
+    index: &Pseudoaligner<K>,
+    eq_counts: &EqClassCounts,
+    fragment_lengths: Option<&FragmentLengthDist>,
+    vbem: bool,
+) -> Abundances {
+    let eff_lengths = match fragment_lengths {
+        Some(dist) => dist.effective_lengths(&index.tx_lengths),
+        None => effective_lengths(&index.tx_lengths, MEAN_FRAGMENT_LENGTH),
+    };
+    let est_counts = if vbem {
+        info!("Running VBEM on {} equivalence classes", eq_counts.len());
+        em(eq_counts, &eff_lengths, Some(VBEM_PRIOR))
+    } else {
+        info!("Running EM on {} equivalence classes", eq_counts.len());
+        em(eq_counts, &eff_lengths, None)
+    };
+
+    Abundances {
+        eff_lengths,
+        est_counts,
+    }
+}
+
+/// Length of each transcript that a fragment of `mean_frag_len` can start from.
+/// Transcripts shorter than the fragment keep their full length.
+pub fn effective_lengths(tx_lengths: &[usize], mean_frag_len: f64) -> Vec<f64> {
+    tx_lengths
+        .iter()
+        .map(|&len| {
+            let eff_len = len as f64 - mean_frag_len + 1.0;
+            if eff_len < 1.0 {
+                len as f64
+            } else {
+                eff_len
+            }
+        })
+        .collect()
+}
+
+/// Expectation-maximization of the read counts assigned to each transcript.
+/// With a `vbem_prior` the variational Bayes update is used instead, with
+/// that prior count on every transcript.
+pub fn em(eq_counts: &EqClassCounts, eff_lengths: &[f64], vbem_prior: Option<f64>) -> Vec<f64> {
+    let num_tx = eff_lengths.len();
+    let total_reads: f64 = eq_counts.values().map(|&c| c as f64).sum();
+
+    let mut alpha = vec![total_reads / num_tx as f64; num_tx];
+    let mut next_alpha = vec![0.0; num_tx];
+    let mut weights = vec![0.0; num_tx];
+
+    let mut round = 0;
+    while round < EM_MAX_ROUNDS {
+        // per-transcript weight of a read landing in it
+        match vbem_prior {
+            Some(prior) => {
+                let total_alpha: f64 = alpha.iter().map(|a| a + prior).sum();
+                let digamma_total = digamma(total_alpha);
+                for t in 0..num_tx {
+                    weights[t] = (digamma(alpha[t] + prior) - digamma_total).exp() / eff_lengths[t];
+                }
+            }
+            None => {
+                for t in 0..num_tx {
+                    weights[t] = alpha[t] / eff_lengths[t];
+                }
+            }
+        }
+
+        for a in next_alpha.iter_mut() {
+            *a = 0.0;
+        }
+
+        for (eq_class, &count) in eq_counts {
+            let denom: f64 = eq_class.iter().map(|&t| weights[t as usize]).sum();
+            if denom <= 0.0 {
+                continue;
+            }
+
+            let count = count as f64;
+            for &t in eq_class {
+                next_alpha[t as usize] += count * weights[t as usize] / denom;
+            }
+        }
+
+        let converged = alpha
+            .iter()
+            .zip(next_alpha.iter())
+            .all(|(a, next)| *next <= EM_ALPHA_LIMIT || (next - a).abs() / next <= EM_TOLERANCE);
+
+        std::mem::swap(&mut alpha, &mut next_alpha);
+        round += 1;
+
+        if converged && round >= EM_MIN_ROUNDS {
+            break;
+        }
+    }
+
+    info!("EM finished after {} rounds", round);
+
+    // zero out the transcripts that are effectively absent
+    for a in alpha.iter_mut() {
+        if *a < EM_ALPHA_LIMIT / 10.0 {
+            *a = 0.0;
+        }
+    }
+
+    alpha
+}
+
+/// Digamma function, by recurrence up to x >= 6 followed by the asymptotic expansion.
+fn digamma(x: f64) -> f64 {
+    let mut x = x;
+    let mut result = 0.0;
+
+    while x < 6.0 {
+        result -= 1.0 / x;
+        x += 1.0;
+    }
+
+    let f = 1.0 / (x * x);
+    result + x.ln()
+        - 0.5 / x
+        - f * (1.0 / 12.0 - f * (1.0 / 120.0 - f * (1.0 / 252.0 - f * (1.0 / 240.0 - f / 132.0))))
+}
+
+/// Write the abundance of every transcript to `abundance.tsv`; intron
+/// targets are left out.
+pub fn write_abundance_tsv<K: Kmer, P: AsRef<Path>>(
+    index: &Pseudoaligner<K>,
+    abundances: &Abundances,
+    outdir: P,
+) -> Result<(), Error> {
+    let mut outfile = open_file("abundance.tsv", outdir)?;
+
+    outfile.write_all(ABUNDANCE_HEADER_STRING.as_bytes())?;
+
+    let tpm = abundances.tpm(&index.target_kinds);
+    for (t, tx_name) in index.tx_names.iter().enumerate() {
+        // introns only take their share of the reads, see `velocity`
+        if index.target_kinds[t] == TargetKind::Intron {
+            continue;
+        }
+        write!(
+            outfile,
+            "{}\t{}\t{}\t{}\t{}\n",
+            tx_name,
+            index.tx_lengths[t],
+            abundances.eff_lengths[t],
+            abundances.est_counts[t],
+            tpm[t]
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Read counts rolled up from equivalence classes to genes.
+#[derive(Debug)]
+pub struct GeneCounts {
+    pub gene_ids: Vec<String>,
+    // reads compatible with this gene only
+    pub unique_counts: Vec<u32>,
+    // reads compatible with this gene and at least one other
+    pub ambiguous_counts: Vec<u32>,
+    // unique reads, plus the share of the multi-gene reads if those are distributed
+    pub counts: Vec<f64>,
+}
+
+/// Assign every gene in the index an id. Returns the gene names ordered by
+/// id and the gene id of each transcript.
+pub fn tx_to_gene_ids<K: Kmer>(index: &Pseudoaligner<K>) -> Result<(Vec<String>, Vec<u32>), Error> {
+    let mut gene_ids: Vec<String> = index.tx_gene_mapping.values().cloned().collect();
+    gene_ids.sort();
+    gene_ids.dedup();
+
+    let tx_genes = {
+        let gene_idx: HashMap<&String, u32> = gene_ids
+            .iter()
+            .enumerate()
+            .map(|(id, gene)| (gene, id as u32))
+            .collect();
+
+        index
+            .tx_names
+            .iter()
+            .map(|tx_name| {
+                index
+                    .tx_gene_mapping
+                    .get(tx_name)
+                    .map(|gene| gene_idx[gene])
+                    .ok_or_else(|| {
+                        MappingError::Parse(format!(
+                            "transcript {} has no gene in the index",
+                            tx_name
+                        ))
+                    })
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    Ok((gene_ids, tx_genes))
+}
+
+/// The sorted set of genes an equivalence class of transcripts touches.
+pub fn gene_eq_class(eq_class: &[u32], tx_genes: &[u32]) -> Vec<u32> {
+    let mut genes: Vec<u32> = eq_class.iter().map(|&t| tx_genes[t as usize]).collect();
+    genes.sort();
+    genes.dedup();
+    genes
+}
+
+/// Roll the equivalence class counts of a mapping run up to genes.
+pub fn gene_counts<K: Kmer>(
+    index: &Pseudoaligner<K>,
+    eq_counts: &EqClassCounts,
+    distribute_multigene: bool,
+) -> Result<GeneCounts, Error> {
+    let (gene_ids, tx_genes) = tx_to_gene_ids(index)?;
+    let (unique_counts, ambiguous_counts, counts) =
+        count_genes(eq_counts, &tx_genes, gene_ids.len(), distribute_multigene);
+
+    Ok(GeneCounts {
+        gene_ids,
+        unique_counts,
+        ambiguous_counts,
+        counts,
+    })
+}
+
+/// Count the gene-unique and multi-gene reads of each gene. Multi-gene reads
+/// are optionally split between their genes in proportion to the genes'
+/// unique counts, or evenly if none of them have unique reads.
+fn count_genes(
+    eq_counts: &EqClassCounts,
+    tx_genes: &[u32],
+    num_genes: usize,
+    distribute_multigene: bool,
+) -> (Vec<u32>, Vec<u32>, Vec<f64>) {
+    let mut unique_counts = vec![0; num_genes];
+    let mut ambiguous_counts = vec![0; num_genes];
+    let mut multigene_classes = Vec::new();
+
+    for (eq_class, &count) in eq_counts {
+        let genes = gene_eq_class(eq_class, tx_genes);
+        match genes.len() {
+            0 => (),
+            1 => unique_counts[genes[0] as usize] += count,
+            _ => {
+                for &g in &genes {
+                    ambiguous_counts[g as usize] += count;
+                }
+                multigene_classes.push((genes, count));
+            }
+        }
+    }
+
+    let mut counts: Vec<f64> = unique_counts.iter().map(|&c| c as f64).collect();
+    if distribute_multigene {
+        for (genes, count) in multigene_classes {
+            let total_unique: u32 = genes.iter().map(|&g| unique_counts[g as usize]).sum();
+            for &g in &genes {
+                let share = if total_unique > 0 {
+                    unique_counts[g as usize] as f64 / total_unique as f64
+                } else {
+                    1.0 / genes.len() as f64
+                };
+                counts[g as usize] += count as f64 * share;
+            }
+        }
+    }
+
+    (unique_counts, ambiguous_counts, counts)
+}
+
+pub fn write_gene_counts_tsv<P: AsRef<Path>>(
+    gene_counts: &GeneCounts,
+    outdir: P,
+) -> Result<(), Error> {
+    let mut outfile = open_file("genes.tsv", outdir)?;
+
+    outfile.write_all(GENE_COUNTS_HEADER_STRING.as_bytes())?;
+
+    for (g, gene_id) in gene_counts.gene_ids.iter().enumerate() {
+        write!(
+            outfile,
+            "{}\t{}\t{}\t{}\n",
+            gene_id,
+            gene_counts.unique_counts[g],
+            gene_counts.ambiguous_counts[g],
+            gene_counts.counts[g]
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_data::test_dir;
+
+    fn counts(classes: &[(&[u32], u32)]) -> EqClassCounts {
+        classes
+            .iter()
+            .map(|(eq_class, count)| (eq_class.to_vec(), *count))
+            .collect()
+    }
+
+    #[test]
+    fn test_em_unique_reads() {
+        let eq_counts = counts(&[(&[0], 30), (&[1], 10)]);
+        let est_counts = em(&eq_counts, &[100.0, 100.0], None);
+
+        assert!((est_counts[0] - 30.0).abs() < 1e-6);
+        assert!((est_counts[1] - 10.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_em_shared_reads() {
+        // the shared reads should be split 3:1 following the unique reads
+        let eq_counts = counts(&[(&[0], 30), (&[1], 10), (&[0, 1], 40), (&[2], 0)]);
+
+        for prior in &[None, Some(VBEM_PRIOR)] {
+            let est_counts = em(&eq_counts, &[100.0, 100.0, 100.0], *prior);
+            let total: f64 = est_counts.iter().sum();
+
+            assert!((total - 80.0).abs() < 1e-3);
+            assert!((est_counts[0] - 60.0).abs() < 0.5);
+            assert!((est_counts[1] - 20.0).abs() < 0.5);
+            assert!(est_counts[2] < 0.5);
+        }
+    }
+
+    #[test]
+    fn test_tpm() {
+        let abundances = Abundances {
+            eff_lengths: vec![100.0, 300.0, 100.0],
+            est_counts: vec![10.0, 30.0, 50.0],
+        };
+        let kinds = [TargetKind::Mature, TargetKind::Mature, TargetKind::Intron];
+        assert_eq!(abundances.tpm(&kinds), vec![5e5, 5e5, 0.0]);
+    }
+
+    #[test]
+    fn test_count_genes() {
+        // transcripts 0, 1 -> gene 0; transcript 2 -> gene 1; transcript 3 -> gene 2
+        let tx_genes = vec![0, 0, 1, 2];
+        let eq_counts = counts(&[
+            (&[0, 1], 30),
+            (&[2], 10),
+            (&[1, 2], 8),
+            (&[3], 0),
+            (&[0, 3], 4),
+        ]);
+
+        let (unique, ambiguous, counts) = count_genes(&eq_counts, &tx_genes, 3, false);
+        assert_eq!(unique, vec![30, 10, 0]);
+        assert_eq!(ambiguous, vec![12, 8, 4]);
+        assert_eq!(counts, vec![30.0, 10.0, 0.0]);
+
+        let (_, _, counts) = count_genes(&eq_counts, &tx_genes, 3, true);
+        assert_eq!(counts, vec![30.0 + 6.0 + 4.0, 10.0 + 2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_eq_counts_roundtrip() -> Result<(), Error> {
+        let outdir = test_dir("eq_counts_roundtrip");
+
+        let eq_counts = counts(&[(&[0, 1], 30), (&[2], 10), (&[1, 2, 5], 8)]);
+        write_eq_counts_tsv(&eq_counts, &outdir)?;
+        let loaded = read_eq_counts_tsv(outdir.join("eq_class_counts.tsv"))?;
+
+        assert_eq!(loaded, eq_counts);
+
+        let bad = outdir.join("bad_counts.tsv");
+        std::fs::write(&bad, "transcripts\tcount\n0,x\t3\n")?;
+        let err = read_eq_counts_tsv(&bad).unwrap_err();
+        match err.downcast_ref::<MappingError>() {
+            Some(MappingError::Parse(_)) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_digamma() {
+        // digamma(1) = -euler_gamma
+        assert!((digamma(1.0) + 0.577_215_664_901_532_9).abs() < 1e-10);
+        assert!((digamma(10.0) - 2.251_752_589_066_721).abs() < 1e-10);
+    }
+}
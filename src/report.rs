@@ -0,0 +1,127 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! JSON report of a mapping run, `run_info.json`, with the read totals of the
+//! run and a description of the index, for aggregation by QC tools.
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use failure::Error;
+use serde::Serialize;
+
+use crate::index_file::IndexHeader;
+use crate::pseudoaligner::MappingSummary;
+use crate::utils::open_file;
+
+#[derive(Serialize, Debug)]
+pub struct RunReport {
+    // version of the crate that ran the mapping
+    pub crate_version: String,
+    // command line of the run
+    pub call: String,
+    // start of the run, in seconds since the Unix epoch
+    pub start_time: u64,
+    pub runtime_seconds: f64,
+    pub num_threads: usize,
+    pub num_reads: usize,
+    pub num_pseudoaligned: usize,
+    pub percent_pseudoaligned: f64,
+    // reads whose kmers hit the index, but with no transcript in common
+    pub num_empty_intersections: usize,
+    // reads too short to hold a single kmer
+    pub num_too_short: usize,
+    // single-cell reads without a cell barcode and UMI
+    pub num_untagged: usize,
+    // read bases covered per pseudoaligned read
+    pub mean_coverage: f64,
+    pub num_eq_classes: usize,
+    pub index_path: String,
+    pub index: IndexHeader,
+}
+
+impl RunReport {
+    pub fn new(
+        summary: &MappingSummary,
+        index_path: &str,
+        index: &IndexHeader,
+        start_time: u64,
+        runtime: Duration,
+        num_threads: usize,
+    ) -> RunReport {
+        let percent_pseudoaligned = if summary.num_reads > 0 {
+            summary.num_mapped as f64 * 100.0 / summary.num_reads as f64
+        } else {
+            0.0
+        };
+
+        RunReport {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            call: std::env::args().collect::<Vec<_>>().join(" "),
+            start_time,
+            runtime_seconds: runtime.as_secs() as f64 + f64::from(runtime.subsec_millis()) / 1e3,
+            num_threads,
+            num_reads: summary.num_reads,
+            num_pseudoaligned: summary.num_mapped,
+            percent_pseudoaligned,
+            num_empty_intersections: summary.num_empty_intersections,
+            num_too_short: summary.num_too_short,
+            num_untagged: summary.num_untagged,
+            mean_coverage: summary.mean_coverage(),
+            num_eq_classes: summary.eq_counts.len(),
+            index_path: index_path.to_string(),
+            index: index.clone(),
+        }
+    }
+
+    /// Write the report to `run_info.json` in `outdir`.
+    pub fn write_json<P: AsRef<Path>>(&self, outdir: P) -> Result<(), Error> {
+        let mut outfile = open_file("run_info.json", outdir)?;
+        serde_json::to_writer_pretty(&mut outfile, self)?;
+        writeln!(outfile)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::IndexConfig;
+
+    #[test]
+    fn test_run_report() -> Result<(), Error> {
+        let summary = MappingSummary {
+            num_reads: 4,
+            num_mapped: 2,
+            num_empty_intersections: 1,
+            num_too_short: 1,
+            total_coverage: 150,
+            eq_counts: vec![(vec![0, 1], 2)].into_iter().collect(),
+            ..MappingSummary::default()
+        };
+
+        let header = IndexHeader {
+            crate_version: "0.4.0".to_string(),
+            index_config: IndexConfig::default(),
+            num_transcripts: 2,
+            fasta_digest: "0123456789abcdef".to_string(),
+        };
+        let report = RunReport::new(
+            &summary,
+            "index.idx",
+            &header,
+            1_600_000_000,
+            Duration::from_millis(2500),
+            4,
+        );
+
+        let json: serde_json::Value = serde_json::to_value(&report)?;
+        assert_eq!(json["num_reads"], 4);
+        assert_eq!(json["percent_pseudoaligned"], 50.0);
+        assert_eq!(json["mean_coverage"], 75.0);
+        assert_eq!(json["num_eq_classes"], 1);
+        assert_eq!(json["runtime_seconds"], 2.5);
+        assert_eq!(json["index"]["index_config"]["kmer_size"], 20);
+        assert_eq!(json["index"]["num_transcripts"], 2);
+        Ok(())
+    }
+}
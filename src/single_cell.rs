@@ -0,0 +1,463 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Barcode and UMI aware mapping of single-cell reads, producing UMI
+//! counts per cell and gene, and per splicing status with an intron index.
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use bio::io::fastq;
+use debruijn::Kmer;
+use failure::Error;
+use log::{info, warn};
+
+use crate::config::MappingConfig;
+use crate::pseudoaligner::{
+    intersect, map_reads_parallel, record_to_dna_string, MappingSummary, Pseudoaligner, ReadMapping,
+};
+use crate::quant::{gene_eq_class, tx_to_gene_ids};
+use crate::utils::{self, open_file};
+use crate::velocity::{splicing_status, SplicingStatus};
+
+/// Cell barcode and UMI of a read.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellTag {
+    pub barcode: String,
+    pub umi: String,
+}
+
+impl CellTag {
+    pub fn new(barcode: &str, umi: &str) -> CellTag {
+        CellTag {
+            barcode: barcode.to_owned(),
+            umi: umi.to_owned(),
+        }
+    }
+
+    /// Take the barcode and UMI from the start of a barcode read, e.g. R1 of
+    /// 10x Chromium libraries. Returns None if the read is too short.
+    pub fn from_barcode_read(
+        record: &fastq::Record,
+        barcode_len: usize,
+        umi_len: usize,
+    ) -> Option<CellTag> {
+        let seq = record.seq();
+        if seq.len() < barcode_len + umi_len {
+            return None;
+        }
+
+        let barcode = str::from_utf8(&seq[..barcode_len]).ok()?;
+        let umi = str::from_utf8(&seq[barcode_len..barcode_len + umi_len]).ok()?;
+        Some(CellTag::new(barcode, umi))
+    }
+
+    /// Take the barcode and UMI from the `CB:Z:` and `UB:Z:` tags in the read
+    /// description, or failing that from a `<name>_<barcode>_<umi>` read name
+    /// as written by umi_tools. The read name fields are only taken as a
+    /// barcode and UMI if they are nucleotides of `barcode_len` and `umi_len`
+    /// bases, so ordinary read names with underscores aren't misread.
+    pub fn from_header(
+        record: &fastq::Record,
+        barcode_len: usize,
+        umi_len: usize,
+    ) -> Option<CellTag> {
+        if let Some(desc) = record.desc() {
+            let mut barcode = None;
+            let mut umi = None;
+            for token in desc.split_whitespace() {
+                if token.starts_with("CB:Z:") {
+                    barcode = Some(&token[5..]);
+                } else if token.starts_with("UB:Z:") {
+                    umi = Some(&token[5..]);
+                }
+            }
+
+            if let (Some(barcode), Some(umi)) = (barcode, umi) {
+                return Some(CellTag::new(barcode, umi));
+            }
+        }
+
+        let mut tokens = record.id().rsplitn(3, '_');
+        let umi = tokens.next()?;
+        let barcode = tokens.next()?;
+        // there must be a read name in front of the barcode
+        tokens.next()?;
+        if is_tag_seq(barcode, barcode_len) && is_tag_seq(umi, umi_len) {
+            Some(CellTag::new(barcode, umi))
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `s` is a barcode or UMI sequence of `len` bases.
+fn is_tag_seq(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b"ACGTN".contains(&b))
+}
+
+/// Equivalence class of every UMI seen in each cell. Reads sharing a
+/// barcode and UMI come from the same molecule, so their equivalence
+/// classes are intersected.
+#[derive(Debug, Default)]
+pub struct UmiCounts {
+    umi_classes: HashMap<CellTag, Vec<u32>>,
+}
+
+/// UMI counts of each gene in each cell.
+#[derive(Debug)]
+pub struct CellGeneMatrix {
+    pub barcodes: Vec<String>,
+    pub gene_ids: Vec<String>,
+    // (gene index, cell index) -> UMI count
+    pub counts: BTreeMap<(u32, u32), u32>,
+}
+
+/// UMI counts of each gene in each cell by splicing status, for RNA
+/// velocity. The matrices share the barcodes and genes of the gene matrix.
+#[derive(Debug)]
+pub struct VelocityMatrices {
+    pub spliced: CellGeneMatrix,
+    pub unspliced: CellGeneMatrix,
+    pub ambiguous: CellGeneMatrix,
+}
+
+impl UmiCounts {
+    pub fn new() -> UmiCounts {
+        UmiCounts::default()
+    }
+
+    pub fn add(&mut self, tag: CellTag, eq_class: &[u32]) {
+        match self.umi_classes.entry(tag) {
+            Entry::Occupied(mut e) => intersect(e.get_mut(), eq_class),
+            Entry::Vacant(e) => {
+                e.insert(eq_class.to_vec());
+            }
+        }
+    }
+
+    pub fn num_umis(&self) -> usize {
+        self.umi_classes.len()
+    }
+
+    /// Count the molecules of each gene in each cell. UMIs whose reads are
+    /// compatible with more than one gene, or none, are not counted.
+    pub fn gene_matrix<K: Kmer>(&self, index: &Pseudoaligner<K>) -> Result<CellGeneMatrix, Error> {
+        let (gene_ids, tx_genes) = tx_to_gene_ids(index)?;
+        Ok(self.count_matrix(gene_ids, &tx_genes, |_| true))
+    }
+
+    /// Count the molecules of each gene in each cell by splicing status,
+    /// with an index built with introns.
+    pub fn velocity_matrices<K: Kmer>(
+        &self,
+        index: &Pseudoaligner<K>,
+    ) -> Result<VelocityMatrices, Error> {
+        let (gene_ids, tx_genes) = tx_to_gene_ids(index)?;
+        let matrix = |status| {
+            self.count_matrix(gene_ids.clone(), &tx_genes, |eq_class| {
+                splicing_status(eq_class, &index.target_kinds) == Some(status)
+            })
+        };
+
+        Ok(VelocityMatrices {
+            spliced: matrix(SplicingStatus::Spliced),
+            unspliced: matrix(SplicingStatus::Unspliced),
+            ambiguous: matrix(SplicingStatus::Ambiguous),
+        })
+    }
+
+    /// Count the molecules of a single gene whose equivalence class passes
+    /// `filter`, in each cell. Every cell seen gets a column.
+    fn count_matrix<F: Fn(&[u32]) -> bool>(
+        &self,
+        gene_ids: Vec<String>,
+        tx_genes: &[u32],
+        filter: F,
+    ) -> CellGeneMatrix {
+        let mut barcodes: Vec<String> = self
+            .umi_classes
+            .keys()
+            .map(|tag| tag.barcode.clone())
+            .collect();
+        barcodes.sort();
+        barcodes.dedup();
+
+        let mut counts = BTreeMap::new();
+        {
+            let cell_idx: HashMap<&String, u32> = barcodes
+                .iter()
+                .enumerate()
+                .map(|(id, barcode)| (barcode, id as u32))
+                .collect();
+
+            for (tag, eq_class) in &self.umi_classes {
+                let genes = gene_eq_class(eq_class, tx_genes);
+                if genes.len() == 1 && filter(eq_class) {
+                    *counts
+                        .entry((genes[0], cell_idx[&tag.barcode]))
+                        .or_insert(0) += 1;
+                }
+            }
+        }
+
+        CellGeneMatrix {
+            barcodes,
+            gene_ids,
+            counts,
+        }
+    }
+}
+
+/// Map single-cell reads with the barcode and UMI in the read headers, see
+/// `CellTag::from_header`.
+pub fn map_tagged_records<K, I>(
+    records: I,
+    barcode_len: usize,
+    umi_len: usize,
+    index: &Pseudoaligner<K>,
+    config: &MappingConfig,
+    num_threads: usize,
+) -> Result<(MappingSummary, UmiCounts), Error>
+where
+    K: Kmer + Sync + Send,
+    I: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+{
+    info!("Done Reading index");
+    info!("Starting Multi-threaded Single-cell Mapping");
+
+    let atomic_reader = Arc::new(Mutex::new(records));
+
+    let next_read = || {
+        utils::get_next_record(&atomic_reader).map(|result_record| -> Result<_, Error> {
+            let record = result_record?;
+            let seq = record_to_dna_string(&record)?;
+            let mut read =
+                ReadMapping::new(record.id(), index.map_read_stranded(&seq, config), config);
+            read.too_short = seq.len() < K::k();
+            Ok((read, CellTag::from_header(&record, barcode_len, umi_len)))
+        })
+    };
+
+    count_umis(next_read, num_threads)
+}
+
+/// Map single-cell reads with the barcode and UMI at the start of a
+/// separate barcode read, e.g. R1 and R2 of 10x Chromium libraries.
+pub fn map_barcoded_records<K, I1, I2>(
+    barcode_records: I1,
+    cdna_records: I2,
+    barcode_len: usize,
+    umi_len: usize,
+    index: &Pseudoaligner<K>,
+    config: &MappingConfig,
+    num_threads: usize,
+) -> Result<(MappingSummary, UmiCounts), Error>
+where
+    K: Kmer + Sync + Send,
+    I1: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+    I2: Iterator<Item = Result<fastq::Record, io::Error>> + Send,
+{
+    info!("Done Reading index");
+    info!("Starting Multi-threaded Single-cell Mapping");
+
+    let atomic_reader = Arc::new(Mutex::new((barcode_records, cdna_records)));
+
+    let next_read = || {
+        utils::get_next_record_pair(&atomic_reader).map(|result_pair| -> Result<_, Error> {
+            let (barcode_record, cdna_record) = result_pair?;
+            let seq = record_to_dna_string(&cdna_record)?;
+            let mut read = ReadMapping::new(
+                cdna_record.id(),
+                index.map_read_stranded(&seq, config),
+                config,
+            );
+            read.too_short = seq.len() < K::k();
+            let tag = CellTag::from_barcode_read(&barcode_record, barcode_len, umi_len);
+            Ok((read, tag))
+        })
+    };
+
+    count_umis(next_read, num_threads)
+}
+
+/// Map the reads from `next_read` and count the UMIs of the mapped reads.
+/// Reads without a cell tag can't be counted; they are tallied in the
+/// summary.
+fn count_umis<F>(next_read: F, num_threads: usize) -> Result<(MappingSummary, UmiCounts), Error>
+where
+    F: Fn() -> Option<Result<(ReadMapping, Option<CellTag>), Error>> + Sync,
+{
+    let mut umi_counts = UmiCounts::new();
+    let mut num_untagged = 0;
+    let mut summary = map_reads_parallel(
+        next_read,
+        |read, tag| {
+            match tag {
+                Some(tag) if read.mapped => umi_counts.add(tag, &read.eq_class),
+                Some(_) => (),
+                None => num_untagged += 1,
+            }
+            Ok(())
+        },
+        num_threads,
+    )?;
+
+    summary.num_untagged = num_untagged;
+    if num_untagged > 0 {
+        warn!(
+            "{} of {} reads have no cell barcode and UMI and are not counted",
+            num_untagged, summary.num_reads
+        );
+    }
+    Ok((summary, umi_counts))
+}
+
+/// Write the cell by gene matrix in the Matrix Market layout of 10x
+/// feature-barcode matrices: `matrix.mtx`, `barcodes.tsv` and `features.tsv`.
+pub fn write_matrix_market<K: Kmer + Sync + Send, P: AsRef<Path>>(
+    matrix: &CellGeneMatrix,
+    index: &Pseudoaligner<K>,
+    outdir: P,
+) -> Result<(), Error> {
+    write_mtx(matrix, "matrix.mtx", &outdir)?;
+
+    let mut barcodes = BufWriter::new(open_file("barcodes.tsv", &outdir)?);
+    for barcode in &matrix.barcodes {
+        write!(barcodes, "{}\n", barcode)?;
+    }
+
+    let mut features = BufWriter::new(open_file("features.tsv", &outdir)?);
+    for gene_id in &matrix.gene_ids {
+        write!(
+            features,
+            "{}\t{}\tGene Expression\n",
+            gene_id,
+            index.gene_name(gene_id)
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Write the velocity matrices to `spliced.mtx`, `unspliced.mtx` and
+/// `ambiguous.mtx`, next to the barcodes and features of
+/// `write_matrix_market`.
+pub fn write_velocity_matrices<P: AsRef<Path>>(
+    matrices: &VelocityMatrices,
+    outdir: P,
+) -> Result<(), Error> {
+    write_mtx(&matrices.spliced, "spliced.mtx", &outdir)?;
+    write_mtx(&matrices.unspliced, "unspliced.mtx", &outdir)?;
+    write_mtx(&matrices.ambiguous, "ambiguous.mtx", &outdir)?;
+    Ok(())
+}
+
+fn write_mtx<P: AsRef<Path>>(
+    matrix: &CellGeneMatrix,
+    filename: &str,
+    outdir: P,
+) -> Result<(), Error> {
+    let mut mtx = BufWriter::new(open_file(filename, outdir)?);
+    write!(
+        mtx,
+        "%%MatrixMarket matrix coordinate integer general\n%\n{} {} {}\n",
+        matrix.gene_ids.len(),
+        matrix.barcodes.len(),
+        matrix.counts.len()
+    )?;
+    for (&(gene, cell), count) in &matrix.counts {
+        write!(mtx, "{} {} {}\n", gene + 1, cell + 1, count)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cell_tags() {
+        let record = fastq::Record::with_attrs("r1", Some("CB:Z:AAAC UB:Z:GGTT"), b"ACGT", b"IIII");
+        assert_eq!(
+            CellTag::from_header(&record, 16, 12),
+            Some(CellTag::new("AAAC", "GGTT"))
+        );
+
+        let record = fastq::Record::with_attrs("SRR1.7_AAAC_GGTTN", None, b"ACGT", b"IIII");
+        assert_eq!(
+            CellTag::from_header(&record, 4, 5),
+            Some(CellTag::new("AAAC", "GGTTN"))
+        );
+        // the read name fields must be of the barcode and UMI lengths
+        assert_eq!(CellTag::from_header(&record, 16, 12), None);
+
+        let record = fastq::Record::with_attrs("r1", None, b"ACGT", b"IIII");
+        assert_eq!(CellTag::from_header(&record, 4, 4), None);
+
+        // an ordinary read name with underscores
+        let record = fastq::Record::with_attrs("sample_lane1_1234", None, b"ACGT", b"IIII");
+        assert_eq!(CellTag::from_header(&record, 5, 4), None);
+
+        let record = fastq::Record::with_attrs("r1", None, b"AAACGGTTCC", b"IIIIIIIIII");
+        assert_eq!(
+            CellTag::from_barcode_read(&record, 4, 4),
+            Some(CellTag::new("AAAC", "GGTT"))
+        );
+        assert_eq!(CellTag::from_barcode_read(&record, 8, 4), None);
+    }
+
+    #[test]
+    fn test_umi_dedup() {
+        let mut umi_counts = UmiCounts::new();
+        umi_counts.add(CellTag::new("AAAC", "GGTT"), &[0, 1, 2]);
+        umi_counts.add(CellTag::new("AAAC", "GGTT"), &[1, 2]);
+        umi_counts.add(CellTag::new("AAAC", "GGTT"), &[2, 3]);
+        umi_counts.add(CellTag::new("AAAC", "CCTT"), &[0]);
+        umi_counts.add(CellTag::new("TTTG", "GGTT"), &[0]);
+
+        assert_eq!(umi_counts.num_umis(), 3);
+        assert_eq!(
+            umi_counts.umi_classes[&CellTag::new("AAAC", "GGTT")],
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn test_velocity_counts() {
+        use crate::annotation::TargetKind::*;
+        // targets 0 and 1 are transcripts of gene 0, 2 its intron; 3 is gene 1
+        let target_kinds = [Mature, Mature, Intron, Mature];
+        let tx_genes = [0, 0, 0, 1];
+        let gene_ids = vec!["g0".to_string(), "g1".to_string()];
+
+        let mut umi_counts = UmiCounts::new();
+        umi_counts.add(CellTag::new("AAAC", "GGTT"), &[0, 1]);
+        umi_counts.add(CellTag::new("AAAC", "CCTT"), &[2]);
+        umi_counts.add(CellTag::new("TTTG", "GGTT"), &[1, 2]);
+        umi_counts.add(CellTag::new("TTTG", "CCTT"), &[2, 3]);
+
+        let unspliced = umi_counts.count_matrix(gene_ids.clone(), &tx_genes, |eq_class| {
+            splicing_status(eq_class, &target_kinds) == Some(SplicingStatus::Unspliced)
+        });
+        let ambiguous = umi_counts.count_matrix(gene_ids.clone(), &tx_genes, |eq_class| {
+            splicing_status(eq_class, &target_kinds) == Some(SplicingStatus::Ambiguous)
+        });
+        let all = umi_counts.count_matrix(gene_ids, &tx_genes, |_| true);
+
+        // the multi-gene UMI is in no matrix, but its cell still has a column
+        assert_eq!(all.barcodes, vec!["AAAC", "TTTG"]);
+        assert_eq!(unspliced.barcodes, all.barcodes);
+        assert_eq!(
+            unspliced.counts.into_iter().collect::<Vec<_>>(),
+            vec![((0, 0), 1)]
+        );
+        assert_eq!(
+            ambiguous.counts.into_iter().collect::<Vec<_>>(),
+            vec![((0, 1), 1)]
+        );
+        assert_eq!(all.counts[&(0, 0)], 2);
+    }
+}
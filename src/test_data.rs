@@ -0,0 +1,67 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Fixtures shared by the unit tests: indexes of `test/gencode_small.fa`,
+//! built once per test run, and per-test scratch directories.
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use bio::io::fasta;
+use debruijn::dna_string::DnaString;
+use lazy_static::lazy_static;
+
+use crate::build_index::build_index;
+use crate::config::{IndexConfig, KmerType};
+use crate::pseudoaligner::Pseudoaligner;
+use crate::utils;
+
+pub const SMALL_FASTA: &str = "test/gencode_small.fa";
+
+lazy_static! {
+    static ref SMALL_TRANSCRIPTS: (Vec<DnaString>, Vec<String>, HashMap<String, String>) = {
+        let fasta = fasta::Reader::from_file(SMALL_FASTA).unwrap();
+        utils::read_transcripts(fasta).unwrap()
+    };
+    static ref SMALL_INDEX: Pseudoaligner<KmerType> = small_index_with(&IndexConfig::default());
+    static ref SMALL_POSITIONS_INDEX: Pseudoaligner<KmerType> = small_index_with(&IndexConfig {
+        positions: true,
+        ..IndexConfig::default()
+    });
+}
+
+/// Sequences, names and genes of the transcripts of `SMALL_FASTA`.
+pub fn small_transcripts() -> &'static (Vec<DnaString>, Vec<String>, HashMap<String, String>) {
+    &SMALL_TRANSCRIPTS
+}
+
+/// Index of `SMALL_FASTA` with the default settings.
+pub fn small_index() -> &'static Pseudoaligner<KmerType> {
+    &SMALL_INDEX
+}
+
+/// Index of `SMALL_FASTA` with node positions.
+pub fn small_positions_index() -> &'static Pseudoaligner<KmerType> {
+    &SMALL_POSITIONS_INDEX
+}
+
+/// Build a fresh index of `SMALL_FASTA`, for tests that modify it.
+pub fn small_index_with(index_config: &IndexConfig) -> Pseudoaligner<KmerType> {
+    let (seqs, tx_names, tx_gene_map) = small_transcripts();
+    build_index(seqs, tx_names, tx_gene_map, index_config, 2).unwrap()
+}
+
+/// Bases of the first transcript of `SMALL_FASTA`.
+pub fn first_transcript() -> Vec<u8> {
+    let mut records = fasta::Reader::from_file(SMALL_FASTA).unwrap().records();
+    records.next().unwrap().unwrap().seq().to_vec()
+}
+
+/// Empty scratch directory for one test, unique to the test process so that
+/// concurrent test runs don't share files.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("debruijn_mapping-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -8,77 +8,115 @@ use std::io::{self, BufRead, BufReader, BufWriter};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-use bincode::{self, deserialize_from, serialize_into};
-use failure::{self, Error};
+use bincode::{deserialize_from, serialize_into};
+use failure::Error;
 use flate2::read::MultiGzDecoder;
 use serde::{de::DeserializeOwned, Serialize};
 
 use bio::io::{fasta, fastq};
 use debruijn::dna_string::DnaString;
-use log::info;
+use log::{info, warn};
 
 use crate::config::FastaFormat;
+use crate::error::MappingError;
 
-pub fn write_obj<T: Serialize, P: AsRef<Path> + Debug>(
-    g: &T,
-    filename: P,
-) -> Result<(), bincode::Error> {
-    let f = match File::create(&filename) {
-        Err(err) => panic!("couldn't create file {:?}: {}", filename, err),
-        Ok(f) => f,
-    };
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub fn write_obj<T: Serialize, P: AsRef<Path> + Debug>(g: &T, filename: P) -> Result<(), Error> {
+    let f = File::create(&filename).map_err(|err| MappingError::io(&filename, err))?;
     let mut writer = BufWriter::new(f);
-    serialize_into(&mut writer, &g)
+    serialize_into(&mut writer, &g)?;
+    Ok(())
 }
 
-pub fn read_obj<T: DeserializeOwned, P: AsRef<Path> + Debug>(
-    filename: P,
-) -> Result<T, bincode::Error> {
-    let f = match File::open(&filename) {
-        Err(err) => panic!("couldn't open file {:?}: {}", filename, err),
-        Ok(f) => f,
-    };
+pub fn read_obj<T: DeserializeOwned, P: AsRef<Path> + Debug>(filename: P) -> Result<T, Error> {
+    let f = File::open(&filename).map_err(|err| MappingError::io(&filename, err))?;
     let mut reader = BufReader::new(f);
-    deserialize_from(&mut reader)
+    let obj = deserialize_from(&mut reader)
+        .map_err(|err| MappingError::Parse(format!("{:?}: {}", filename, err)))?;
+    Ok(obj)
 }
 
-/// Open a (possibly gzipped) file into a BufReader.
-fn _open_with_gz<P: AsRef<Path>>(p: P) -> Result<Box<dyn BufRead>, Error> {
-    let r = File::open(p.as_ref())?;
+/// Buffered input stream handed to the FASTA/FASTQ parsers.
+pub type InputReader = Box<dyn BufRead + Send>;
 
-    if p.as_ref().extension().unwrap() == "gz" {
-        let gz = MultiGzDecoder::new(r);
-        let buf_reader = BufReader::with_capacity(32 * 1024, gz);
-        Ok(Box::new(buf_reader))
+/// Open a file into a BufReader, transparently decompressing (multi-member)
+/// gzip input. The path `-` reads from stdin.
+pub fn open_with_gz<P: AsRef<Path>>(p: P) -> Result<InputReader, Error> {
+    let r: Box<dyn io::Read + Send> = if p.as_ref() == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        let f = File::open(p.as_ref()).map_err(|err| MappingError::io(p.as_ref(), err))?;
+        Box::new(f)
+    };
+    let mut buf_reader = BufReader::with_capacity(32 * 1024, r);
+
+    // detect gzip from the magic bytes so that piped input works too
+    if buf_reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        let gz = MultiGzDecoder::new(buf_reader);
+        Ok(Box::new(BufReader::with_capacity(32 * 1024, gz)))
     } else {
-        let buf_reader = BufReader::with_capacity(32 * 1024, r);
         Ok(Box::new(buf_reader))
     }
 }
 
-pub fn read_transcripts(
-    reader: fasta::Reader<File>,
+pub fn fastq_reader<P: AsRef<Path>>(p: P) -> Result<fastq::Reader<InputReader>, Error> {
+    Ok(fastq::Reader::new(open_with_gz(p)?))
+}
+
+pub fn fasta_reader<P: AsRef<Path>>(p: P) -> Result<fasta::Reader<InputReader>, Error> {
+    Ok(fasta::Reader::new(open_with_gz(p)?))
+}
+
+pub fn read_transcripts<R: io::Read>(
+    reader: fasta::Reader<R>,
+) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>), Error> {
+    read_transcripts_with_tx2gene(reader, None)
+}
+
+/// Read the transcripts of a FASTA file along with the gene of each
+/// transcript. Genes are looked up in `tx2gene` if given, e.g. from
+/// `annotation::read_tx2gene`, and otherwise parsed from the FASTA headers.
+pub fn read_transcripts_with_tx2gene<R: io::Read>(
+    reader: fasta::Reader<R>,
+    tx2gene: Option<&HashMap<String, String>>,
 ) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>), Error> {
     let mut seqs = Vec::new();
     let mut transcript_counter = 0;
     let mut tx_ids = Vec::new();
     let mut tx_to_gene_map = HashMap::new();
-    let mut fasta_format = FastaFormat::Unknown;
+    // transcripts named by their header only, or missing from `tx2gene`
+    let mut num_plain = 0;
+    let mut num_unmapped = 0;
 
     info!("Reading transcripts from Fasta file");
     for result in reader.records() {
         // obtain record or fail with error
-        let record = result?;
+        let record = result.map_err(|err| {
+            MappingError::Parse(format!("FASTA record {}: {}", transcript_counter + 1, err))
+        })?;
 
         // Sequence
         let dna_string = DnaString::from_acgt_bytes_hashn(record.seq(), record.id().as_bytes());
         seqs.push(dna_string);
 
-        if let FastaFormat::Unknown = fasta_format {
-            fasta_format = detect_fasta_format(&record)?;
+        // detected per record, so files with mixed headers can be read
+        let fasta_format = detect_fasta_format(&record)?;
+        let tx_id = extract_tx_id(&record, &fasta_format)?;
+        if fasta_format == FastaFormat::Plain {
+            num_plain += 1;
         }
 
-        let (tx_id, gene_id) = extract_tx_gene_id(&record, &fasta_format);
+        // the header only has to name the gene of transcripts `tx2gene` lacks
+        let gene_id = match tx2gene.and_then(|tx2gene| tx2gene.get(&tx_id)) {
+            Some(gene_id) => gene_id.clone(),
+            None => {
+                if tx2gene.is_some() {
+                    num_unmapped += 1;
+                }
+                extract_tx_gene_id(&record, &fasta_format)?.1
+            }
+        };
 
         tx_ids.push(tx_id.clone());
         tx_to_gene_map.insert(tx_id, gene_id);
@@ -90,68 +128,332 @@ pub fn read_transcripts(
         "Done reading the Fasta file; Found {} sequences",
         transcript_counter
     );
+    if tx2gene.is_some() && num_unmapped > 0 {
+        warn!(
+            "{} transcripts are missing from the transcript to gene map; using their FASTA headers",
+            num_unmapped
+        );
+    } else if tx2gene.is_none() && num_plain > 0 {
+        warn!(
+            "{} transcripts have no gene in their FASTA header and are treated as their own gene",
+            num_plain
+        );
+    }
 
     Ok((seqs, tx_ids, tx_to_gene_map))
 }
 
+/// Header layout of a transcript record. Headers that match none of the
+/// known layouts are taken as plain transcript ids.
 pub fn detect_fasta_format(record: &fasta::Record) -> Result<FastaFormat, Error> {
+    if record.id().is_empty() {
+        return Err(MappingError::FastaFormat(fasta_header(record)).into());
+    }
+
+    // gffread names transcripts by their RefSeq accession too, e.g.
+    // `>NM_000014.6 gene=A2M`, so its gene attribute comes first
+    let desc = record.desc().unwrap_or("");
+    if desc.starts_with("gene=") {
+        return Ok(FastaFormat::Gffread);
+    }
+
+    // RefSeq ids may be wrapped as `gi|<gi>|ref|<accession>|`, so test
+    // them before Gencode's `|` separated ids
+    if refseq_accession(record.id()).is_some() {
+        return Ok(FastaFormat::Refseq);
+    }
+
     let id_tokens: Vec<&str> = record.id().split('|').collect();
-    if id_tokens.len() == 9 {
+    if id_tokens.len() >= 2 && !id_tokens[1].is_empty() {
         return Ok(FastaFormat::Gencode);
     }
 
-    let desc_tokens: Vec<&str> = record.desc().unwrap().split(' ').collect();
-    if desc_tokens.len() >= 1 {
-        let gene_tokens: Vec<&str> = desc_tokens[0].split('=').collect();
-        if gene_tokens.len() == 2 && gene_tokens[0] == "gene" {
-            return Ok(FastaFormat::Gffread);
-        }
-    } else if desc_tokens.len() == 5 {
+    if desc.split(' ').any(|token| token.starts_with("gene:")) {
         return Ok(FastaFormat::Ensembl);
     }
-    Err(failure::err_msg("Failed to detect FASTA header format."))
+    Ok(FastaFormat::Plain)
 }
 
-pub fn extract_tx_gene_id(record: &fasta::Record, fasta_format: &FastaFormat) -> (String, String) {
+/// RefSeq transcript accession (`NM_`, `NR_`, `XM_` or `XR_`) in a FASTA id.
+fn refseq_accession(id: &str) -> Option<&str> {
+    id.split('|').find(|token| {
+        ["NM_", "NR_", "XM_", "XR_"]
+            .iter()
+            .any(|prefix| token.starts_with(prefix))
+    })
+}
+
+/// Transcript id of a FASTA record, without parsing its gene.
+pub fn extract_tx_id(record: &fasta::Record, fasta_format: &FastaFormat) -> Result<String, Error> {
+    let header_error = || MappingError::FastaFormat(fasta_header(record));
+
+    match *fasta_format {
+        FastaFormat::Gencode => Ok(record.id().split('|').next().unwrap_or("").to_string()),
+        FastaFormat::Refseq => Ok(refseq_accession(record.id())
+            .ok_or_else(header_error)?
+            .to_string()),
+        FastaFormat::Ensembl | FastaFormat::Gffread | FastaFormat::Plain => {
+            Ok(record.id().to_string())
+        }
+        FastaFormat::Unknown => Err(header_error().into()),
+    }
+}
+
+pub fn extract_tx_gene_id(
+    record: &fasta::Record,
+    fasta_format: &FastaFormat,
+) -> Result<(String, String), Error> {
+    let header_error = || MappingError::FastaFormat(fasta_header(record));
+    let tx_id = extract_tx_id(record, fasta_format)?;
+
     match *fasta_format {
         FastaFormat::Gencode => {
             let id_tokens: Vec<&str> = record.id().split('|').collect();
-            let tx_id = id_tokens[0].to_string();
-            let gene_id = id_tokens[1].to_string();
+            let gene_id = id_tokens.get(1).ok_or_else(header_error)?.to_string();
             // (human readable name)
             // let gene_name = id_tokens[5].to_string();
-            (tx_id, gene_id)
+            Ok((tx_id, gene_id))
         }
         FastaFormat::Ensembl => {
-            let tx_id = record.id().to_string();
-            let desc_tokens: Vec<&str> = record.desc().unwrap().split(' ').collect();
-            let gene_tmp: Vec<&str> = desc_tokens[2].split(':').collect();
-            let gene_id = gene_tmp[1].to_string();
-            (tx_id, gene_id)
+            let gene_id = record
+                .desc()
+                .unwrap_or("")
+                .split(' ')
+                .find(|token| token.starts_with("gene:"))
+                .map(|token| &token["gene:".len()..])
+                .filter(|gene_id| !gene_id.is_empty())
+                .ok_or_else(header_error)?
+                .to_string();
+            Ok((tx_id, gene_id))
         }
         FastaFormat::Gffread => {
-            let id_tokens: Vec<&str> = record.id().split(' ').collect();
-            let tx_id = id_tokens[0].to_string();
-            let desc_tokens: Vec<&str> = record.desc().unwrap().split(' ').collect();
+            let desc_tokens: Vec<&str> = record.desc().unwrap_or("").split(' ').collect();
             let gene_tokens: Vec<&str> = desc_tokens[0].split('=').collect();
-            let gene_id = gene_tokens[1].to_string();
-            (tx_id, gene_id)
+            let gene_id = gene_tokens.get(1).ok_or_else(header_error)?.to_string();
+            Ok((tx_id, gene_id))
         }
-        FastaFormat::Unknown => {
-            panic!("fasta_format was uninitialized");
+        FastaFormat::Refseq => {
+            // `Homo sapiens alpha-2-macroglobulin (A2M), transcript variant 1, mRNA`
+            let desc = record.desc().unwrap_or("");
+            let gene_id = desc
+                .rfind('(')
+                .and_then(|start| {
+                    let symbol = &desc[start + 1..];
+                    symbol.find(')').map(|end| &symbol[..end])
+                })
+                .filter(|symbol| !symbol.is_empty())
+                .ok_or_else(header_error)?
+                .to_string();
+            Ok((tx_id, gene_id))
         }
+        FastaFormat::Plain => Ok((tx_id.clone(), tx_id)),
+        FastaFormat::Unknown => Err(header_error().into()),
     }
 }
 
-pub fn get_next_record<R: io::Read>(
-    reader: &Arc<Mutex<fastq::Records<R>>>,
-) -> Option<Result<fastq::Record, io::Error>> {
+/// Full header line of a FASTA record, for error messages.
+fn fasta_header(record: &fasta::Record) -> String {
+    match record.desc() {
+        Some(desc) => format!(">{} {}", record.id(), desc),
+        None => format!(">{}", record.id()),
+    }
+}
+
+pub fn get_next_record<I: Iterator<Item = Result<fastq::Record, io::Error>>>(
+    reader: &Arc<Mutex<I>>,
+) -> Option<Result<fastq::Record, Error>> {
     let mut lock = reader.lock().unwrap();
-    lock.next()
+    lock.next().map(|record| record.map_err(fastq_error))
+}
+
+/// Pull the next record from both mate files of a paired-end run. Fails if
+/// the files are of unequal length or the mate names don't agree.
+pub fn get_next_record_pair<I1, I2>(
+    reader: &Arc<Mutex<(I1, I2)>>,
+) -> Option<Result<(fastq::Record, fastq::Record), Error>>
+where
+    I1: Iterator<Item = Result<fastq::Record, io::Error>>,
+    I2: Iterator<Item = Result<fastq::Record, io::Error>>,
+{
+    let mut lock = reader.lock().unwrap();
+    let (records1, records2) = &mut *lock;
+
+    match (records1.next(), records2.next()) {
+        (None, None) => None,
+        (Some(record1), Some(record2)) => Some(check_mates(record1, record2)),
+        _ => Some(Err(MappingError::Parse(
+            "paired-end FASTQ files contain different numbers of reads".to_string(),
+        )
+        .into())),
+    }
+}
+
+fn check_mates(
+    record1: Result<fastq::Record, io::Error>,
+    record2: Result<fastq::Record, io::Error>,
+) -> Result<(fastq::Record, fastq::Record), Error> {
+    let record1 = record1.map_err(fastq_error)?;
+    let record2 = record2.map_err(fastq_error)?;
+
+    if mate_name(record1.id()) != mate_name(record2.id()) {
+        return Err(MappingError::Parse(format!(
+            "mate names don't match: {} and {}",
+            record1.id(),
+            record2.id()
+        ))
+        .into());
+    }
+    Ok((record1, record2))
+}
+
+// The FASTQ reader reports malformed records as I/O errors.
+fn fastq_error(err: io::Error) -> Error {
+    MappingError::Parse(format!("FASTQ record: {}", err)).into()
+}
+
+/// Strip the `/1` or `/2` mate suffix from a read name, if present.
+pub fn mate_name(read_id: &str) -> &str {
+    if read_id.ends_with("/1") || read_id.ends_with("/2") {
+        &read_id[..read_id.len() - 2]
+    } else {
+        read_id
+    }
 }
 
 pub fn open_file<P: AsRef<Path>>(filename: &str, outdir: P) -> Result<File, Error> {
     let out_fn = outdir.as_ref().join(filename);
-    let outfile = File::create(&out_fn)?;
+    let outfile = File::create(&out_fn).map_err(|err| MappingError::io(&out_fn, err))?;
     Ok(outfile)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_data::test_dir;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_open_multi_member_gz() -> Result<(), Error> {
+        let path = test_dir("multi_member_gz").join("reads.fq.gz");
+
+        // two concatenated gzip members, as written by e.g. `cat a.gz b.gz`
+        let mut file = File::create(&path)?;
+        for member in &["@r1\nACGT\n+\nIIII\n", "@r2\nTTGA\n+\nIIII\n"] {
+            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+            gz.write_all(member.as_bytes())?;
+            file.write_all(&gz.finish()?)?;
+        }
+        drop(file);
+
+        let mut contents = String::new();
+        open_with_gz(&path)?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "@r1\nACGT\n+\nIIII\n@r2\nTTGA\n+\nIIII\n");
+
+        let ids: Vec<String> = fastq_reader(&path)?
+            .records()
+            .map(|r| r.map(|r| r.id().to_owned()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(ids, vec!["r1", "r2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_input_errors() {
+        let missing = read_obj::<Vec<u32>, _>("test/no_such_file.bin").unwrap_err();
+        match missing.downcast_ref::<MappingError>() {
+            Some(MappingError::Io { .. }) => (),
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+
+        // a Gencode header without the gene field
+        let record = fasta::Record::with_attrs("ENST00000456328.2", None, b"ACGT");
+        let bad_header = extract_tx_gene_id(&record, &FastaFormat::Gencode).unwrap_err();
+        match bad_header.downcast_ref::<MappingError>() {
+            Some(MappingError::FastaFormat(header)) => assert_eq!(header, ">ENST00000456328.2"),
+            other => panic!("expected a FASTA format error, got {:?}", other),
+        }
+        assert!(extract_tx_gene_id(&record, &FastaFormat::Unknown).is_err());
+
+        // a RefSeq header without the gene symbol
+        let record = fasta::Record::with_attrs("NM_000014.6", Some("mRNA"), b"ACGT");
+        let bad_header = extract_tx_gene_id(&record, &FastaFormat::Refseq).unwrap_err();
+        match bad_header.downcast_ref::<MappingError>() {
+            Some(MappingError::FastaFormat(header)) => assert_eq!(header, ">NM_000014.6 mRNA"),
+            other => panic!("expected a FASTA format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_fasta_header_formats() -> Result<(), Error> {
+        let fasta = b">ENST00000456328.2|ENSG00000223972.5|OTTHUMG00000000961.2|OTTHUMT00000362751.1|RP11-34P13.1-002|DDX11L1|1657|processed_transcript|
+ACGT
+>ENST00000632684.1 cdna chromosome:GRCh38:7:142786213:142786624:1 gene:ENSG00000282431.1 gene_biotype:TR_D_gene
+ACGT
+>rna-NM_1 gene=geneC
+ACGT
+>NM_000014.6 Homo sapiens alpha-2-macroglobulin (A2M), transcript variant 1, mRNA
+ACGT
+>gi|1234|ref|XR_001737578.2| PREDICTED: Homo sapiens uncharacterized LOC105378947 (LOC105378947), ncRNA
+ACGT
+>contig_17 assembled transcript
+ACGT
+";
+        let (_, tx_names, tx_gene_map) = read_transcripts(fasta::Reader::new(&fasta[..]))?;
+        let genes: Vec<&str> = tx_names.iter().map(|tx| tx_gene_map[tx].as_str()).collect();
+        assert_eq!(
+            tx_names,
+            vec![
+                "ENST00000456328.2",
+                "ENST00000632684.1",
+                "rna-NM_1",
+                "NM_000014.6",
+                "XR_001737578.2",
+                "contig_17"
+            ]
+        );
+        assert_eq!(
+            genes,
+            vec![
+                "ENSG00000223972.5",
+                "ENSG00000282431.1",
+                "geneC",
+                "A2M",
+                "LOC105378947",
+                "contig_17"
+            ]
+        );
+
+        // an explicit map takes precedence over the headers
+        let tx2gene: HashMap<String, String> =
+            vec![("contig_17".to_string(), "gene17".to_string())]
+                .into_iter()
+                .collect();
+        let (_, _, tx_gene_map) =
+            read_transcripts_with_tx2gene(fasta::Reader::new(&fasta[..]), Some(&tx2gene))?;
+        assert_eq!(tx_gene_map["contig_17"], "gene17");
+        assert_eq!(tx_gene_map["NM_000014.6"], "A2M");
+
+        // a RefSeq header without a gene symbol is fine if tx2gene has it
+        let fasta = b">NM_000014.6 mRNA\nACGT\n";
+        assert!(read_transcripts(fasta::Reader::new(&fasta[..])).is_err());
+        let tx2gene: HashMap<String, String> = vec![("NM_000014.6".to_string(), "A2M".to_string())]
+            .into_iter()
+            .collect();
+        let (_, tx_names, tx_gene_map) =
+            read_transcripts_with_tx2gene(fasta::Reader::new(&fasta[..]), Some(&tx2gene))?;
+        assert_eq!(tx_names, vec!["NM_000014.6"]);
+        assert_eq!(tx_gene_map["NM_000014.6"], "A2M");
+
+        // gffread output of a RefSeq annotation
+        let record = fasta::Record::with_attrs("NM_000014.6", Some("gene=A2M"), b"ACGT");
+        let fasta_format = detect_fasta_format(&record)?;
+        assert_eq!(fasta_format, FastaFormat::Gffread);
+        assert_eq!(
+            extract_tx_gene_id(&record, &fasta_format)?,
+            ("NM_000014.6".to_string(), "A2M".to_string())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
+
+//! Spliced and unspliced read counts per gene, for RNA velocity. Needs an
+//! index built with the introns of the genes as targets, see
+//! `Annotation::add_introns`. A read compatible with mature transcripts only
+//! is spliced, one compatible with introns only is unspliced, and one
+//! compatible with both, e.g. inside an exon next to an intron, is ambiguous.
+use std::io::Write;
+use std::path::Path;
+
+use debruijn::Kmer;
+use failure::Error;
+
+use crate::annotation::TargetKind;
+use crate::pseudoaligner::Pseudoaligner;
+use crate::quant::{gene_eq_class, tx_to_gene_ids, EqClassCounts};
+use crate::utils::open_file;
+
+const VELOCITY_HEADER_STRING: &str = "gene_id\tgene_name\tspliced\tunspliced\tambiguous\n";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplicingStatus {
+    Spliced,
+    Unspliced,
+    Ambiguous,
+}
+
+/// Splicing status of the reads of an equivalence class, `None` for an
+/// empty class.
+pub fn splicing_status(eq_class: &[u32], target_kinds: &[TargetKind]) -> Option<SplicingStatus> {
+    let mut mature = false;
+    let mut intron = false;
+    for &t in eq_class {
+        match target_kinds[t as usize] {
+            TargetKind::Mature => mature = true,
+            TargetKind::Intron => intron = true,
+        }
+    }
+
+    match (mature, intron) {
+        (true, false) => Some(SplicingStatus::Spliced),
+        (false, true) => Some(SplicingStatus::Unspliced),
+        (true, true) => Some(SplicingStatus::Ambiguous),
+        (false, false) => None,
+    }
+}
+
+/// Reads of each gene by splicing status. Reads compatible with more than
+/// one gene are not counted.
+#[derive(Debug)]
+pub struct VelocityCounts {
+    pub gene_ids: Vec<String>,
+    pub spliced: Vec<u32>,
+    pub unspliced: Vec<u32>,
+    pub ambiguous: Vec<u32>,
+}
+
+pub fn velocity_counts<K: Kmer>(
+    index: &Pseudoaligner<K>,
+    eq_counts: &EqClassCounts,
+) -> Result<VelocityCounts, Error> {
+    let (gene_ids, tx_genes) = tx_to_gene_ids(index)?;
+    let mut counts = VelocityCounts {
+        spliced: vec![0; gene_ids.len()],
+        unspliced: vec![0; gene_ids.len()],
+        ambiguous: vec![0; gene_ids.len()],
+        gene_ids,
+    };
+
+    for (eq_class, &count) in eq_counts {
+        let genes = gene_eq_class(eq_class, &tx_genes);
+        if genes.len() != 1 {
+            continue;
+        }
+
+        let g = genes[0] as usize;
+        match splicing_status(eq_class, &index.target_kinds) {
+            Some(SplicingStatus::Spliced) => counts.spliced[g] += count,
+            Some(SplicingStatus::Unspliced) => counts.unspliced[g] += count,
+            Some(SplicingStatus::Ambiguous) => counts.ambiguous[g] += count,
+            None => (),
+        }
+    }
+
+    Ok(counts)
+}
+
+pub fn write_velocity_tsv<K: Kmer + Sync + Send, P: AsRef<Path>>(
+    index: &Pseudoaligner<K>,
+    counts: &VelocityCounts,
+    outdir: P,
+) -> Result<(), Error> {
+    let mut outfile = open_file("velocity.tsv", outdir)?;
+
+    outfile.write_all(VELOCITY_HEADER_STRING.as_bytes())?;
+
+    for (g, gene_id) in counts.gene_ids.iter().enumerate() {
+        writeln!(
+            outfile,
+            "{}\t{}\t{}\t{}\t{}",
+            gene_id,
+            index.gene_name(gene_id),
+            counts.spliced[g],
+            counts.unspliced[g],
+            counts.ambiguous[g]
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_splicing_status() {
+        use TargetKind::*;
+        let kinds = [Mature, Mature, Intron];
+
+        assert_eq!(
+            splicing_status(&[0, 1], &kinds),
+            Some(SplicingStatus::Spliced)
+        );
+        assert_eq!(
+            splicing_status(&[2], &kinds),
+            Some(SplicingStatus::Unspliced)
+        );
+        assert_eq!(
+            splicing_status(&[1, 2], &kinds),
+            Some(SplicingStatus::Ambiguous)
+        );
+        assert_eq!(splicing_status(&[], &kinds), None);
+    }
+}